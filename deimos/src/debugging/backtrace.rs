@@ -0,0 +1,100 @@
+// Stack unwinding by frame pointers, which the target spec forces on for all code.
+//
+// Every frame starts with the caller's rbp followed by the return address:
+//
+//   rbp -> | saved rbp | return address | ...
+//
+// so the chain of saved rbps leads up the stack. Each frame is checked to be mapped before
+// it is read, a broken chain ends the walk instead of faulting inside a crash dump.
+
+use core::arch::asm;
+use x86_64::{VirtAddr, structures::paging::Translate};
+use crate::{kprintln, memory_management::page_table::MAPPER};
+use super::symbols::Symbolized;
+
+const MAX_FRAMES: usize = 32;
+
+// The frame pointer of the function this is inlined into
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    rbp
+}
+
+// Calls f with the return address of every frame above the one at frame_pointer, innermost
+// first. Stops at a null, misaligned or unmapped frame pointer, or when the page tables
+// are locked and frames can not be checked.
+pub fn walk(frame_pointer: u64, mut f: impl FnMut(u64)) {
+    let Some(mapper) = MAPPER.try_lock() else { return };
+    let Some(mapper) = mapper.as_ref() else { return };
+    let readable = |addr: u64| VirtAddr::try_new(addr).is_ok_and(|addr| mapper.translate_addr(addr).is_some());
+
+    let mut rbp = frame_pointer;
+    for _ in 0..MAX_FRAMES {
+        if rbp == 0 || !rbp.is_multiple_of(8) || !readable(rbp) || !readable(rbp + 8) {
+            return;
+        }
+        let frame = rbp as *const u64;
+        let (saved_rbp, return_address) = unsafe { (frame.read(), frame.add(1).read()) };
+        if return_address == 0 {
+            return;
+        }
+        f(return_address);
+        // The stack grows down, so callers' frames are always higher. This also ends the
+        // walk where an exception switched stacks, print_interrupted covers that side.
+        if saved_rbp <= rbp {
+            return;
+        }
+        rbp = saved_rbp;
+    }
+}
+
+// Prints the backtrace of the caller
+#[inline(never)]
+pub fn print() {
+    kprintln!("Backtrace:");
+    let mut index = 0;
+    walk(frame_pointer(), |address| {
+        print_frame(index, address);
+        index += 1;
+    });
+}
+
+// Prints the backtrace of the code an exception interrupted. Takes the frame pointer of the
+// exception handler, whose frame starts with the interrupted code's rbp.
+pub fn print_interrupted(instruction_pointer: VirtAddr, handler_frame_pointer: u64) {
+    kprintln!("Backtrace:");
+    print_frame(0, instruction_pointer.as_u64());
+    let mut index = 1;
+    let interrupted_rbp = unsafe { (handler_frame_pointer as *const u64).read() };
+    walk(interrupted_rbp, |address| {
+        print_frame(index, address);
+        index += 1;
+    });
+}
+
+fn print_frame(index: usize, address: u64) {
+    kprintln!("  #{:<2} {}", index, Symbolized(address));
+}
+
+#[test_case]
+fn test_backtrace_walk() {
+    #[inline(never)]
+    fn depth(levels: usize) -> usize {
+        if levels > 0 {
+            // black_box keeps this from being a tail call, so every level keeps its frame
+            return core::hint::black_box(depth(levels - 1));
+        }
+        let mut frames = 0;
+        walk(frame_pointer(), |address| {
+            assert_ne!(address, 0);
+            frames += 1;
+        });
+        frames
+    }
+
+    let shallow = depth(0);
+    assert!(shallow >= 1);
+    assert_eq!(depth(3), (shallow + 3).min(MAX_FRAMES));
+}
@@ -0,0 +1,453 @@
+// A GDB remote serial protocol stub on COM2, built with the gdb_stub feature.
+//
+// Breakpoint and debug exceptions stop in handle_trap, which reports the stop to GDB and
+// serves its requests until it continues or single steps. The kernel stops in the stub once
+// at boot, so GDB can attach and set breakpoints before anything else runs:
+//
+//   make debug
+//   gdb deimos/target/deimos_target/release/deimos -ex 'target remote :4321'
+//
+// Breakpoints are int3 bytes patched into the code (Z0 packets) and single steps set the
+// trap flag. Memory is only accessed where the page tables map it, a bad address from GDB
+// gets an error reply instead of faulting inside the stub.
+
+use core::fmt::{self, Write};
+use uart_16550::SerialPort;
+use x86_64::{
+    VirtAddr,
+    instructions::segmentation::{Segment, DS, ES, FS, GS},
+    registers::control::{Cr0, Cr0Flags},
+};
+use crate::{hardware_interface::{exceptions::TrapFrame, serial::SERIAL2}, memory_management::page_table::is_mapped};
+
+const PACKET_SIZE: usize = 1024;
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xcc;
+const TRAP_FLAG: u64 = 1 << 8;
+const SIGTRAP: u8 = 5;
+
+// Error replies carry an errno
+const EFAULT: u8 = 14;
+const EINVAL: u8 = 22;
+const ENOSPC: u8 = 28;
+
+// GDB's x86-64 register numbers, the general purpose registers are 0 to 15 in the order
+// rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8 to r15. The FPU and SSE registers that come
+// after gs are not sent, GDB shows them as unavailable.
+const RSP: usize = 7;
+const RIP: usize = 16;
+const EFLAGS: usize = 17;
+const CS: usize = 18;
+const SS: usize = 19;
+const REGISTERS: usize = 24;
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    original: u8,
+}
+
+static BREAKPOINTS: spin::Mutex<[Option<Breakpoint>; MAX_BREAKPOINTS]> =
+    spin::Mutex::new([None; MAX_BREAKPOINTS]);
+
+// Serves GDB until it resumes execution. Returns false without doing anything when the stub
+// is already running, for a breakpoint inside the stub itself, so the caller reports it.
+pub fn handle_trap(vector: u8, frame: &mut TrapFrame) -> bool {
+    let Some(mut serial) = SERIAL2.try_lock() else { return false };
+    let Some(mut breakpoints) = BREAKPOINTS.try_lock() else { return false };
+    let mut stub = Stub {
+        serial: &mut serial,
+        breakpoints: &mut breakpoints,
+        frame,
+        reply: Packet::new(),
+    };
+    stub.stopped(vector);
+    stub.serve();
+    true
+}
+
+enum Action {
+    Reply,
+    Resume,
+}
+
+struct Stub<'a> {
+    serial: &'a mut SerialPort,
+    breakpoints: &'a mut [Option<Breakpoint>; MAX_BREAKPOINTS],
+    frame: &'a mut TrapFrame,
+    reply: Packet,
+}
+
+impl Stub<'_> {
+    fn stopped(&mut self, vector: u8) {
+        let rip = self.frame.stack_frame.instruction_pointer.as_u64();
+        let flags = self.frame.stack_frame.cpu_flags & !TRAP_FLAG;
+        self.set_register(EFLAGS, flags);
+        // int3 leaves rip after itself, GDB expects it on the breakpoint it set
+        if vector == 3 && self.breakpoint(rip.wrapping_sub(1)).is_some() {
+            self.set_register(RIP, rip - 1);
+        }
+    }
+
+    fn serve(&mut self) {
+        let mut request = [0; PACKET_SIZE];
+        let _ = write!(self.reply, "S{:02x}", SIGTRAP);
+        self.send_reply();
+        loop {
+            let len = self.receive(&mut request);
+            self.reply.clear();
+            match self.command(&request[..len]) {
+                Ok(Action::Reply) => {}
+                Ok(Action::Resume) => return,
+                Err(errno) => {
+                    self.reply.clear();
+                    let _ = write!(self.reply, "E{:02x}", errno);
+                }
+            }
+            self.send_reply();
+        }
+    }
+
+    fn command(&mut self, request: &[u8]) -> Result<Action, u8> {
+        let Some((&kind, args)) = request.split_first() else { return Ok(Action::Reply) };
+        match kind {
+            b'?' => {
+                let _ = write!(self.reply, "S{:02x}", SIGTRAP);
+            }
+            b'g' => {
+                for n in 0..REGISTERS {
+                    self.reply_register(n);
+                }
+            }
+            b'G' => {
+                let mut hex = args;
+                for n in 0..REGISTERS {
+                    let size = self.register(n).map_or(0, |(_, size)| size);
+                    if hex.len() < size * 2 {
+                        break;
+                    }
+                    let (value, rest) = hex.split_at(size * 2);
+                    self.set_register(n, decode_le(value).ok_or(EINVAL)?);
+                    hex = rest;
+                }
+                self.reply.push(b"OK");
+            }
+            b'p' => {
+                let n = parse_hex(args).ok_or(EINVAL)? as usize;
+                if self.register(n).is_none() {
+                    return Err(EINVAL);
+                }
+                self.reply_register(n);
+            }
+            b'P' => {
+                let (n, value) = split(args, b'=').ok_or(EINVAL)?;
+                let n = parse_hex(n).ok_or(EINVAL)? as usize;
+                if self.register(n).is_none() {
+                    return Err(EINVAL);
+                }
+                self.set_register(n, decode_le(value).ok_or(EINVAL)?);
+                self.reply.push(b"OK");
+            }
+            b'm' => {
+                let (addr, len) = parse_range(args).ok_or(EINVAL)?;
+                let len = len.min(PACKET_SIZE as u64 / 2);
+                if !is_mapped(addr, len) {
+                    return Err(EFAULT);
+                }
+                for addr in addr..addr + len {
+                    let byte = match self.breakpoint(addr) {
+                        Some(breakpoint) => breakpoint.original,
+                        None => unsafe { core::ptr::read_volatile(addr as *const u8) },
+                    };
+                    let _ = write!(self.reply, "{:02x}", byte);
+                }
+            }
+            b'M' => {
+                let (range, data) = split(args, b':').ok_or(EINVAL)?;
+                let (addr, len) = parse_range(range).ok_or(EINVAL)?;
+                if data.len() as u64 != len.saturating_mul(2) {
+                    return Err(EINVAL);
+                }
+                if !is_mapped(addr, len) {
+                    return Err(EFAULT);
+                }
+                for (addr, hex) in (addr..).zip(data.chunks(2)) {
+                    let byte = parse_hex(hex).ok_or(EINVAL)? as u8;
+                    // Writes over a breakpoint change what is restored when it is removed
+                    match self.breakpoint(addr) {
+                        Some(breakpoint) => breakpoint.original = byte,
+                        None => unsafe { poke(addr, byte) },
+                    }
+                }
+                self.reply.push(b"OK");
+            }
+            b'c' | b's' => {
+                if !args.is_empty() {
+                    self.set_register(RIP, parse_hex(args).ok_or(EINVAL)?);
+                }
+                let flags = self.frame.stack_frame.cpu_flags & !TRAP_FLAG;
+                let step = if kind == b's' { TRAP_FLAG } else { 0 };
+                self.set_register(EFLAGS, flags | step);
+                return Ok(Action::Resume);
+            }
+            b'Z' | b'z' => {
+                let (kind, location) = split(args, b',').ok_or(EINVAL)?;
+                if kind != b"0" {
+                    return Ok(Action::Reply); // only software breakpoints
+                }
+                let (addr, _) = split(location, b',').ok_or(EINVAL)?;
+                let addr = parse_hex(addr).ok_or(EINVAL)?;
+                if request[0] == b'Z' {
+                    self.insert_breakpoint(addr)?;
+                } else {
+                    self.remove_breakpoint(addr);
+                }
+                self.reply.push(b"OK");
+            }
+            // The kernel can not be killed, both leave it running without breakpoints
+            b'D' | b'k' => {
+                for slot in self.breakpoints.iter_mut() {
+                    if let Some(breakpoint) = slot.take() {
+                        unsafe { poke(breakpoint.addr, breakpoint.original) };
+                    }
+                }
+                let flags = self.frame.stack_frame.cpu_flags & !TRAP_FLAG;
+                self.set_register(EFLAGS, flags);
+                if kind == b'D' {
+                    self.reply.push(b"OK");
+                    self.send_reply();
+                }
+                return Ok(Action::Resume);
+            }
+            b'q' if args.starts_with(b"Supported") => {
+                let _ = write!(self.reply, "PacketSize={:x}", PACKET_SIZE);
+            }
+            b'q' if args == b"Attached" => self.reply.push(b"1"),
+            b'H' => self.reply.push(b"OK"),
+            // An empty reply tells GDB the packet is not supported
+            _ => {}
+        }
+        Ok(Action::Reply)
+    }
+
+    // The value and size in bytes of register n
+    fn register(&mut self, n: usize) -> Option<(u64, usize)> {
+        if let Some(register) = general_register(self.frame, n) {
+            return Some((*register, 8));
+        }
+        let stack_frame = &self.frame.stack_frame;
+        Some(match n {
+            RSP => (stack_frame.stack_pointer.as_u64(), 8),
+            RIP => (stack_frame.instruction_pointer.as_u64(), 8),
+            EFLAGS => (stack_frame.cpu_flags, 4),
+            CS => (stack_frame.code_segment, 4),
+            SS => (stack_frame.stack_segment, 4),
+            // The trap did not touch the data segments, so they are still the interrupted code's
+            20 => (DS::get_reg().0 as u64, 4),
+            21 => (ES::get_reg().0 as u64, 4),
+            22 => (FS::get_reg().0 as u64, 4),
+            23 => (GS::get_reg().0 as u64, 4),
+            _ => return None,
+        })
+    }
+
+    // Changes take effect when the trap returns. Segment registers are left alone.
+    fn set_register(&mut self, n: usize, value: u64) {
+        if let Some(register) = general_register(self.frame, n) {
+            *register = value;
+            return;
+        }
+        let mut stack_frame = unsafe { self.frame.stack_frame.as_mut() };
+        match n {
+            RSP => stack_frame.update(|frame| frame.stack_pointer = VirtAddr::new_truncate(value)),
+            RIP => stack_frame.update(|frame| frame.instruction_pointer = VirtAddr::new_truncate(value)),
+            EFLAGS => stack_frame.update(|frame| frame.cpu_flags = value & 0xffff_ffff),
+            _ => {}
+        }
+    }
+
+    fn reply_register(&mut self, n: usize) {
+        if let Some((value, size)) = self.register(n) {
+            for byte in &value.to_le_bytes()[..size] {
+                let _ = write!(self.reply, "{:02x}", byte);
+            }
+        }
+    }
+
+    fn breakpoint(&mut self, addr: u64) -> Option<&mut Breakpoint> {
+        self.breakpoints.iter_mut().flatten().find(|breakpoint| breakpoint.addr == addr)
+    }
+
+    fn insert_breakpoint(&mut self, addr: u64) -> Result<(), u8> {
+        if self.breakpoint(addr).is_some() {
+            return Ok(());
+        }
+        if !is_mapped(addr, 1) {
+            return Err(EFAULT);
+        }
+        let slot = self.breakpoints.iter_mut().find(|slot| slot.is_none()).ok_or(ENOSPC)?;
+        let original = unsafe { core::ptr::read_volatile(addr as *const u8) };
+        unsafe { poke(addr, INT3) };
+        *slot = Some(Breakpoint { addr, original });
+        Ok(())
+    }
+
+    fn remove_breakpoint(&mut self, addr: u64) {
+        for slot in self.breakpoints.iter_mut() {
+            if let Some(breakpoint) = slot.filter(|breakpoint| breakpoint.addr == addr) {
+                unsafe { poke(addr, breakpoint.original) };
+                *slot = None;
+            }
+        }
+    }
+
+    // The payload of the next packet with a valid checksum, which gets acknowledged. Acks
+    // and interrupt requests in between are skipped, a nack resends the last reply.
+    fn receive(&mut self, buffer: &mut [u8; PACKET_SIZE]) -> usize {
+        loop {
+            match self.serial.receive() {
+                b'$' => {}
+                b'-' => {
+                    self.send_reply();
+                    continue;
+                }
+                _ => continue,
+            }
+            let mut len = 0;
+            let mut overflow = false;
+            loop {
+                let byte = self.serial.receive();
+                if byte == b'#' {
+                    break;
+                }
+                match buffer.get_mut(len) {
+                    Some(slot) => *slot = byte,
+                    None => overflow = true,
+                }
+                len += 1;
+            }
+            let sum = [self.serial.receive(), self.serial.receive()];
+            if !overflow && parse_hex(&sum) == Some(checksum(&buffer[..len]) as u64) {
+                self.serial.send(b'+');
+                return len;
+            }
+            self.serial.send(b'-');
+        }
+    }
+
+    fn send_reply(&mut self) {
+        self.serial.send(b'$');
+        for &byte in self.reply.as_bytes() {
+            self.serial.send(byte);
+        }
+        let _ = write!(self.serial, "#{:02x}", checksum(self.reply.as_bytes()));
+    }
+}
+
+// Registers saved in the trap frame, everything but rsp and the ones the CPU pushed
+fn general_register(frame: &mut TrapFrame, n: usize) -> Option<&mut u64> {
+    Some(match n {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        _ => return None,
+    })
+}
+
+// Writes a byte even where the mapping is read only, which is where breakpoints go
+unsafe fn poke(addr: u64, byte: u8) {
+    let cr0 = Cr0::read();
+    unsafe {
+        Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+        core::ptr::write_volatile(addr as *mut u8, byte);
+        Cr0::write(cr0);
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn split(data: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let at = data.iter().position(|&byte| byte == separator)?;
+    Some((&data[..at], &data[at + 1..]))
+}
+
+fn parse_hex(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 {
+        return None;
+    }
+    hex.iter().try_fold(0, |value, &digit| Some(value << 4 | (digit as char).to_digit(16)? as u64))
+}
+
+// "addr,length" of the memory packets
+fn parse_range(args: &[u8]) -> Option<(u64, u64)> {
+    let (addr, len) = split(args, b',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+// Register values are sent as their bytes in memory order
+fn decode_le(hex: &[u8]) -> Option<u64> {
+    if !hex.len().is_multiple_of(2) || hex.len() > 16 {
+        return None;
+    }
+    hex.chunks(2).enumerate()
+        .try_fold(0, |value, (index, byte)| Some(value | parse_hex(byte)? << (index * 8)))
+}
+
+// A packet payload, without heap allocations so the stub works whatever the kernel holds
+struct Packet {
+    data: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Packet {
+    fn new() -> Self {
+        Packet { data: [0; PACKET_SIZE], len: 0 }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    // Replies are sized to fit, anything beyond the packet size is cut off
+    fn push(&mut self, bytes: &[u8]) {
+        let end = (self.len + bytes.len()).min(PACKET_SIZE);
+        self.data[self.len..end].copy_from_slice(&bytes[..end - self.len]);
+        self.len = end;
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl fmt::Write for Packet {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_gdb_packet_parsing() {
+    assert_eq!(checksum(b"OK"), 0x9a);
+    assert_eq!(parse_range(b"ffff8000dead0000,40"), Some((0xffff_8000_dead_0000, 0x40)));
+    assert_eq!(parse_range(b"10"), None);
+    assert_eq!(parse_hex(b"1g"), None);
+    assert_eq!(decode_le(b"efbeadde"), Some(0xdead_beef));
+    assert_eq!(decode_le(b"abc"), None);
+    assert_eq!(split(b"7=0100", b'='), Some((&b"7"[..], &b"0100"[..])));
+}
@@ -0,0 +1,5 @@
+pub mod backtrace;
+pub mod symbols;
+pub mod monitor;
+#[cfg(feature = "gdb_stub")]
+pub mod gdb;
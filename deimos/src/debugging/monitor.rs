@@ -0,0 +1,193 @@
+// An interactive debug monitor on VGA and serial.
+//
+// Breakpoints nothing else claims and Ctrl+Shift+F12 stop the kernel in run, which reads
+// commands from the keyboard and COM1 until one resumes execution. Interrupts stay disabled
+// meanwhile, so input is polled and the rest of the kernel is frozen where it stopped.
+// Everything here avoids waiting for locks the stopped code might hold.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use pc_keyboard::DecodedKey;
+use x86_64::{
+    VirtAddr,
+    structures::idt::InterruptStackFrame,
+};
+use crate::{
+    kprint, kprintln,
+    hardware_interface::{exceptions::TrapFrame, interrupts, serial},
+    memory_management::{allocator, page_table},
+    time,
+};
+
+const LINE_SIZE: usize = 64;
+const DEFAULT_DUMP_SIZE: u64 = 0x80;
+const MAX_DUMP_SIZE: u64 = 0x1000;
+const HELP: &str = "regs, mem <addr> [len], walk <addr>, heap, tasks, continue";
+
+// What stopped the kernel
+pub enum Stop<'a> {
+    // int3, with every register of the interrupted code
+    Breakpoint(&'a TrapFrame),
+    // The hotkey, only the interrupt frame is known
+    Hotkey(&'a InterruptStackFrame),
+}
+
+impl Stop<'_> {
+    fn stack_frame(&self) -> &InterruptStackFrame {
+        match self {
+            Stop::Breakpoint(frame) => &frame.stack_frame,
+            Stop::Hotkey(stack_frame) => stack_frame,
+        }
+    }
+}
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// Runs commands until one resumes execution. Returns at once when the monitor is already
+// running, a breakpoint hit from inside it can not be debugged with it.
+pub fn run(stop: Stop) {
+    if ACTIVE.swap(true, Ordering::Acquire) {
+        return;
+    }
+    kprintln!("Kernel monitor at {:#x}, {:?} after boot", stop.stack_frame().instruction_pointer.as_u64(),
+        time::uptime());
+    kprintln!("Commands: {}", HELP);
+    let mut line = [0; LINE_SIZE];
+    loop {
+        kprint!("monitor> ");
+        let len = read_line(&mut line);
+        let line = core::str::from_utf8(&line[..len]).unwrap_or("");
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("continue" | "c") => break,
+            Some("regs") => regs(&stop),
+            Some("mem") => match (words.next().and_then(parse_number), words.next().map(parse_number)) {
+                (Some(addr), None) => mem(addr, DEFAULT_DUMP_SIZE),
+                (Some(addr), Some(Some(len))) => mem(addr, len.min(MAX_DUMP_SIZE)),
+                _ => kprintln!("usage: mem <addr> [len]"),
+            },
+            Some("walk") => match words.next().and_then(parse_number) {
+                Some(addr) => walk(addr),
+                None => kprintln!("usage: walk <addr>"),
+            },
+            Some("heap") => heap(),
+            Some("tasks") => kprintln!("No tasks, the kernel has no scheduler yet"),
+            Some("help") => kprintln!("{}", HELP),
+            Some(command) => kprintln!("Unknown command {}, try: {}", command, HELP),
+            None => {}
+        }
+    }
+    ACTIVE.store(false, Ordering::Release);
+}
+
+// Reads a line from whichever input has keys, echoing it. Returns its length.
+fn read_line(line: &mut [u8; LINE_SIZE]) -> usize {
+    let mut len = 0;
+    loop {
+        let Some(character) = read_char() else { continue };
+        match character {
+            '\n' => {
+                kprintln!();
+                return len;
+            }
+            '\x08' => {
+                if len > 0 {
+                    len -= 1;
+                    kprint!("\x08");
+                }
+            }
+            ' '..='~' if len < LINE_SIZE => {
+                line[len] = character as u8;
+                len += 1;
+                kprint!("{}", character);
+            }
+            _ => {}
+        }
+    }
+}
+
+// Terminals send carriage returns and delete where the keyboard has newline and backspace
+fn read_char() -> Option<char> {
+    if let Some(DecodedKey::Unicode(character)) = interrupts::poll_keyboard() {
+        return Some(character);
+    }
+    match serial::try_receive()? {
+        b'\r' => Some('\n'),
+        0x7f => Some('\x08'),
+        byte => Some(byte as char),
+    }
+}
+
+// Hex, with or without 0x
+fn parse_number(word: &str) -> Option<u64> {
+    let digits = word.strip_prefix("0x").unwrap_or(word);
+    u64::from_str_radix(digits, 16).ok()
+}
+
+fn regs(stop: &Stop) {
+    if let Stop::Breakpoint(frame) = stop {
+        kprintln!("rax {:016x}  rbx {:016x}  rcx {:016x}", frame.rax, frame.rbx, frame.rcx);
+        kprintln!("rdx {:016x}  rsi {:016x}  rdi {:016x}", frame.rdx, frame.rsi, frame.rdi);
+        kprintln!("rbp {:016x}  r8  {:016x}  r9  {:016x}", frame.rbp, frame.r8, frame.r9);
+        kprintln!("r10 {:016x}  r11 {:016x}  r12 {:016x}", frame.r10, frame.r11, frame.r12);
+        kprintln!("r13 {:016x}  r14 {:016x}  r15 {:016x}", frame.r13, frame.r14, frame.r15);
+    } else {
+        kprintln!("(general purpose registers are only saved at breakpoints)");
+    }
+    let stack_frame = stop.stack_frame();
+    kprintln!("rip {:016x}  rsp {:016x}  rflags {:08x}", stack_frame.instruction_pointer.as_u64(),
+        stack_frame.stack_pointer.as_u64(), stack_frame.cpu_flags);
+    kprintln!("cs  {:04x}  ss  {:04x}", stack_frame.code_segment, stack_frame.stack_segment);
+}
+
+// 16 bytes per line, followed by them as ASCII
+fn mem(addr: u64, len: u64) {
+    if !page_table::is_mapped(addr, len) {
+        kprintln!("{:#x}..{:#x} is not mapped", addr, addr.wrapping_add(len));
+        return;
+    }
+    for line in (addr..addr + len).step_by(16) {
+        let end = (line + 16).min(addr + len);
+        let bytes = (line..end).map(|addr| unsafe { core::ptr::read_volatile(addr as *const u8) });
+        kprint!("{:016x}: ", line);
+        for byte in bytes.clone() {
+            kprint!("{:02x} ", byte);
+        }
+        kprint!("{:width$}|", "", width = (16 - (end - line) as usize) * 3);
+        for byte in bytes {
+            let printable = byte.is_ascii_graphic() || byte == b' ';
+            kprint!("{}", if printable { byte as char } else { '.' });
+        }
+        kprintln!("|");
+    }
+}
+
+fn walk(addr: u64) {
+    let Ok(addr) = VirtAddr::try_new(addr) else {
+        kprintln!("{:#x} is not canonical", addr);
+        return;
+    };
+    match page_table::translate_verbose(addr) {
+        Some(translation) => kprint!("{}", translation),
+        None => kprintln!("The page tables are locked"),
+    }
+}
+
+fn heap() {
+    let Some(stats) = allocator::try_stats() else {
+        kprintln!("The heap is locked, the kernel stopped inside the allocator");
+        return;
+    };
+    kprintln!("{} KiB heap, {} KiB in use (peak {} KiB), {} KiB free in {} region(s)",
+        stats.heap_size / 1024, stats.bytes_in_use / 1024, stats.peak_usage / 1024,
+        stats.bytes_free / 1024, stats.free_regions);
+    kprintln!("Largest free block {} bytes, {} KiB cached", stats.largest_free_block, stats.bytes_cached / 1024);
+    kprintln!("{} allocations, {} frees, {} failed", stats.allocations, stats.frees, stats.failed_allocations);
+}
+
+#[test_case]
+fn test_monitor_parse_number() {
+    assert_eq!(parse_number("0xb8000"), Some(0xb8000));
+    assert_eq!(parse_number("b8000"), Some(0xb8000));
+    assert_eq!(parse_number("0x"), None);
+    assert_eq!(parse_number("xyz"), None);
+}
@@ -0,0 +1,104 @@
+// The kernel's function symbols, for naming addresses in crash dumps.
+//
+// The table is not known before linking, so this only reserves the .ksymtab section and
+// tools/ksyms patches the sorted table into the linked kernel ELF (`make bootimage` does
+// that). A kernel that was not patched has an empty table and lookups find nothing.
+// The layout is described in tools/ksyms/src/main.rs.
+
+use core::fmt;
+
+// About three times what the release kernel needs. ksyms refuses a table that does not fit,
+// which a debug build with its unoptimized generic instances does not.
+const KSYMTAB_SIZE: usize = 64 * 1024;
+const MAGIC: &[u8; 8] = b"KSYMTAB1";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+
+// Starts out as an empty table, the non zero magic also keeps it from ending up in .bss
+#[used]
+#[unsafe(link_section = ".ksymtab")]
+static KSYMTAB: [u8; KSYMTAB_SIZE] = {
+    let mut table = [0; KSYMTAB_SIZE];
+    let mut i = 0;
+    while i < MAGIC.len() {
+        table[i] = MAGIC[i];
+        i += 1;
+    }
+    table
+};
+
+// The function containing addr and the offset of addr into it
+pub fn lookup(addr: u64) -> Option<(&'static str, u64)> {
+    // The compiler knows what the table held at build time, not what ksyms put there
+    let table: &'static [u8; KSYMTAB_SIZE] = unsafe { &*core::hint::black_box(&raw const KSYMTAB) };
+    if !table.starts_with(MAGIC) {
+        return None;
+    }
+    let count = read_u32(table, 8)? as usize;
+    let strings = read_u32(table, 12)? as usize;
+
+    // The last symbol starting at or below addr
+    let entry = |index: usize| HEADER_SIZE + index * ENTRY_SIZE;
+    let index = partition_point(count, |index| read_u64(table, entry(index)).is_some_and(|start| start <= addr));
+    let entry = entry(index.checked_sub(1)?);
+    let start = read_u64(table, entry)?;
+    let size = read_u32(table, entry + 8)? as u64;
+    if size != 0 && addr >= start + size {
+        return None;
+    }
+
+    let name = table.get(strings + read_u32(table, entry + 12)? as usize..)?;
+    let name = &name[..name.iter().position(|&b| b == 0)?];
+    Some((core::str::from_utf8(name).ok()?, addr - start))
+}
+
+// Formats an address as "0x... name+0x12", or just the address without a symbol
+pub struct Symbolized(pub u64);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)?;
+        if let Some((name, offset)) = lookup(self.0) {
+            write!(f, " {}+{:#x}", name, offset)?;
+        }
+        Ok(())
+    }
+}
+
+// The first index in 0..count for which below is false, below has to be true up to some index
+fn partition_point(count: usize, below: impl Fn(usize) -> bool) -> usize {
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if below(middle) {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    low
+}
+
+fn read_u32(table: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(table.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u64(table: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(table.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+#[test_case]
+fn test_symbol_lookup() {
+    #[inline(never)]
+    fn marker() {}
+
+    // Only a kernel patched by ksyms has symbols, an unpatched one must find nothing at all
+    let addr = marker as *const () as u64;
+    match lookup(addr) {
+        Some((name, offset)) => {
+            assert!(name.ends_with("test_symbol_lookup::marker"), "found {}", name);
+            assert_eq!(offset, 0);
+        }
+        None => assert!(lookup(addr + 1).is_none()),
+    }
+}
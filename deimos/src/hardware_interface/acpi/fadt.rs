@@ -0,0 +1,69 @@
+// The FADT ("FACP" table), with the fixed ACPI hardware registers.
+//
+// ACPI 1.0 ends the table after the flags, later revisions add the reset register and 64 bit
+// addresses. Only the I/O port blocks from the 1.0 part are used, they are what PCs have.
+
+use x86_64::PhysAddr;
+use super::{AcpiError, GenericAddress, read_u16, read_u32};
+
+const MIN_SIZE: usize = 116;
+const RESET_VALUE: usize = 128;
+
+const PM_TIMER_32BIT: u32 = 1 << 8;
+const RESET_SUPPORTED: u32 = 1 << 10;
+const HAS_8042: u16 = 1 << 1;
+
+pub struct Fadt {
+    pub dsdt: PhysAddr,
+    // The ISA IRQ of the system control interrupt
+    pub sci_interrupt: u16,
+    // Writing acpi_enable to the SMI command port hands the hardware from SMM to the OS.
+    // None when the system is already in ACPI mode.
+    pub smi_command: Option<u16>,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event: u16,
+    pub pm1b_event: Option<u16>,
+    pub pm1a_control: u16,
+    pub pm1b_control: Option<u16>,
+    pub pm_timer: Option<u16>,
+    pub pm_timer_32bit: bool,
+    // The CMOS register with the century, None when the RTC has none
+    pub century: Option<u8>,
+    // Whether there is an 8042 keyboard controller, firmware before ACPI 2.0 does not say
+    pub has_8042: Option<bool>,
+    // Writing the value to the register resets the machine
+    pub reset: Option<(GenericAddress, u8)>,
+}
+
+impl Fadt {
+    pub fn parse(table: &[u8]) -> Result<Fadt, AcpiError> {
+        let malformed = AcpiError::Malformed(*b"FACP");
+        if table.len() < MIN_SIZE {
+            return Err(malformed);
+        }
+        let revision = table[8];
+        let port = |offset| read_u32(table, offset).map(|port| port as u16).filter(|&port| port != 0);
+        let flags = read_u32(table, 112).ok_or(malformed)?;
+
+        let reset = GenericAddress::parse(table, 116)
+            .zip(table.get(RESET_VALUE).copied())
+            .filter(|_| flags & RESET_SUPPORTED != 0);
+        Ok(Fadt {
+            dsdt: PhysAddr::try_new(read_u32(table, 40).ok_or(malformed)? as u64).map_err(|_| malformed)?,
+            sci_interrupt: read_u16(table, 46).ok_or(malformed)?,
+            smi_command: port(48),
+            acpi_enable: table[52],
+            acpi_disable: table[53],
+            pm1a_event: port(56).ok_or(malformed)?,
+            pm1b_event: port(60),
+            pm1a_control: port(64).ok_or(malformed)?,
+            pm1b_control: port(68),
+            pm_timer: port(76),
+            pm_timer_32bit: flags & PM_TIMER_32BIT != 0,
+            century: Some(table[108]).filter(|&register| register != 0),
+            has_8042: (revision >= 3).then(|| read_u16(table, 109).unwrap() & HAS_8042 != 0),
+            reset,
+        })
+    }
+}
@@ -0,0 +1,37 @@
+// The HPET table, which says where the high precision event timer's registers are.
+
+use x86_64::PhysAddr;
+use super::{AcpiError, GenericAddress, read_u16, read_u32};
+
+const COUNTER_64BIT: u32 = 1 << 13;
+const LEGACY_REPLACEMENT: u32 = 1 << 15;
+
+pub struct Hpet {
+    pub address: PhysAddr,
+    // Which HPET this is, when there are several
+    pub number: u8,
+    pub comparators: u8,
+    pub counter_64bit: bool,
+    // Whether it can take over the PIT and RTC interrupts
+    pub legacy_replacement: bool,
+    pub vendor_id: u16,
+    // The smallest period periodic mode is good for, in counter ticks
+    pub min_tick: u16,
+}
+
+impl Hpet {
+    pub fn parse(table: &[u8]) -> Result<Hpet, AcpiError> {
+        let malformed = AcpiError::Malformed(*b"HPET");
+        let id = read_u32(table, 36).ok_or(malformed)?;
+        let base = GenericAddress::parse(table, 40).ok_or(malformed)?;
+        Ok(Hpet {
+            address: PhysAddr::try_new(base.address).map_err(|_| malformed)?,
+            number: *table.get(52).ok_or(malformed)?,
+            comparators: (id >> 8 & 0x1f) as u8 + 1,
+            counter_64bit: id & COUNTER_64BIT != 0,
+            legacy_replacement: id & LEGACY_REPLACEMENT != 0,
+            vendor_id: (id >> 16) as u16,
+            min_tick: read_u16(table, 53).ok_or(malformed)?,
+        })
+    }
+}
@@ -0,0 +1,150 @@
+// The MADT ("APIC" table), which lists the interrupt controllers.
+//
+// After the header come the local APIC address and flags, then variable length entries that
+// each start with their type and length.
+
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+use super::{AcpiError, HEADER_SIZE, read_u16, read_u32, read_u64};
+
+const PCAT_COMPAT: u32 = 1 << 0;
+const ENABLED: u32 = 1 << 0;
+
+// The shortest each entry type we read can be, shorter ones are malformed
+fn min_entry_len(kind: u8) -> usize {
+    match kind {
+        0 => 8,  // processor local APIC
+        1 => 12, // I/O APIC
+        2 => 10, // interrupt source override
+        5 => 12, // local APIC address override
+        9 => 16, // processor local x2APIC
+        _ => 2,
+    }
+}
+
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    // Whether the legacy 8259 PICs are there too
+    pub legacy_pics: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApicEntry>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub uid: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: PhysAddr,
+    // The first global system interrupt it handles
+    pub gsi_base: u32,
+}
+
+// An ISA IRQ that is not wired to the same numbered global system interrupt, or not with the
+// ISA default of active high and edge triggered
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+impl Madt {
+    pub fn parse(table: &[u8]) -> Result<Madt, AcpiError> {
+        let malformed = AcpiError::Malformed(*b"APIC");
+        let mut madt = Madt {
+            local_apic_address: PhysAddr::try_new(read_u32(table, HEADER_SIZE).ok_or(malformed)? as u64)
+                .map_err(|_| malformed)?,
+            legacy_pics: read_u32(table, HEADER_SIZE + 4).ok_or(malformed)? & PCAT_COMPAT != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+        };
+
+        let mut entries = table.get(HEADER_SIZE + 8..).ok_or(malformed)?;
+        while entries.len() >= 2 {
+            let len = entries[1] as usize;
+            let entry = entries.get(..len).filter(|_| len >= min_entry_len(entries[0])).ok_or(malformed)?;
+            entries = &entries[len..];
+            match entry[0] {
+                0 => madt.processors.push(Processor {
+                    uid: entry[2] as u32,
+                    apic_id: entry[3] as u32,
+                    enabled: read_u32(entry, 4).ok_or(malformed)? & ENABLED != 0,
+                }),
+                1 => madt.io_apics.push(IoApicEntry {
+                    id: entry[2],
+                    address: PhysAddr::try_new(read_u32(entry, 4).ok_or(malformed)? as u64).map_err(|_| malformed)?,
+                    gsi_base: read_u32(entry, 8).ok_or(malformed)?,
+                }),
+                2 => {
+                    let flags = read_u16(entry, 8).ok_or(malformed)?;
+                    madt.overrides.push(InterruptOverride {
+                        irq: entry[3],
+                        gsi: read_u32(entry, 4).ok_or(malformed)?,
+                        // 0 means the bus default, which is active high and edge for ISA
+                        polarity: if flags & 0b11 == 0b11 { Polarity::ActiveLow } else { Polarity::ActiveHigh },
+                        trigger: if (flags >> 2) & 0b11 == 0b11 { Trigger::Level } else { Trigger::Edge },
+                    });
+                }
+                5 => {
+                    madt.local_apic_address = PhysAddr::try_new(read_u64(entry, 4).ok_or(malformed)?)
+                        .map_err(|_| malformed)?;
+                }
+                9 => madt.processors.push(Processor {
+                    uid: read_u32(entry, 12).ok_or(malformed)?,
+                    apic_id: read_u32(entry, 4).ok_or(malformed)?,
+                    enabled: read_u32(entry, 8).ok_or(malformed)? & ENABLED != 0,
+                }),
+                _ => {}
+            }
+        }
+        Ok(madt)
+    }
+
+    // Where an ISA IRQ arrives and how it is signalled
+    pub fn isa_irq(&self, irq: u8) -> (u32, Polarity, Trigger) {
+        match self.overrides.iter().find(|entry| entry.irq == irq) {
+            Some(entry) => (entry.gsi, entry.polarity, entry.trigger),
+            None => (irq as u32, Polarity::ActiveHigh, Trigger::Edge),
+        }
+    }
+}
+
+#[test_case]
+fn test_madt_rejects_truncated_tables() {
+    let malformed = Some(AcpiError::Malformed(*b"APIC"));
+    // The header, then a local APIC address of 0xfee00000 and no flags
+    let mut table = alloc::vec![0u8; HEADER_SIZE];
+    table.extend_from_slice(&[0x00, 0x00, 0xe0, 0xfe, 0, 0, 0, 0]);
+    assert_eq!(Madt::parse(&table[..HEADER_SIZE + 4]).err(), malformed);
+
+    // A processor entry that ends right after its type and length
+    let mut short = table.clone();
+    short.extend_from_slice(&[0, 2]);
+    assert_eq!(Madt::parse(&short).err(), malformed);
+
+    table.extend_from_slice(&[0, 8, 1, 3, 1, 0, 0, 0]);
+    let madt = Madt::parse(&table).unwrap();
+    assert_eq!(madt.local_apic_address, PhysAddr::new(0xfee0_0000));
+    assert_eq!((madt.processors[0].uid, madt.processors[0].apic_id), (1, 3));
+}
@@ -0,0 +1,80 @@
+// The MCFG table, with the memory mapped PCI configuration space (ECAM) of each segment.
+//
+// Every function gets 4 KiB of configuration space, at an address made of its bus, device and
+// function number. Machines without PCI Express, like QEMU's default i440FX, have no MCFG.
+
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+use super::{AcpiError, HEADER_SIZE, read_u16, read_u64};
+
+const ENTRY_SIZE: usize = 16;
+
+pub struct Mcfg {
+    pub regions: Vec<PciConfigRegion>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PciConfigRegion {
+    pub base: PhysAddr,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl Mcfg {
+    pub fn parse(table: &[u8]) -> Result<Mcfg, AcpiError> {
+        let malformed = AcpiError::Malformed(*b"MCFG");
+        let entries = table.get(HEADER_SIZE + 8..).ok_or(malformed)?;
+        let regions = entries.chunks_exact(ENTRY_SIZE)
+            .map(|entry| Ok(PciConfigRegion {
+                base: PhysAddr::try_new(read_u64(entry, 0).unwrap()).map_err(|_| malformed)?,
+                segment: read_u16(entry, 8).unwrap(),
+                start_bus: entry[10],
+                end_bus: entry[11],
+            }))
+            .collect::<Result<_, _>>()?;
+        Ok(Mcfg { regions })
+    }
+
+    // Where the configuration space of a function is
+    #[allow(dead_code)]
+    pub fn config_address(&self, segment: u16, bus: u8, device: u8, function: u8) -> Option<PhysAddr> {
+        self.regions.iter()
+            .find(|region| region.segment == segment && (region.start_bus..=region.end_bus).contains(&bus))
+            .and_then(|region| region.config_address(bus, device, function))
+    }
+}
+
+impl PciConfigRegion {
+    pub fn config_address(&self, bus: u8, device: u8, function: u8) -> Option<PhysAddr> {
+        if !(self.start_bus..=self.end_bus).contains(&bus) || device >= 32 || function >= 8 {
+            return None;
+        }
+        let offset = ((bus - self.start_bus) as u64) << 20 | (device as u64) << 15 | (function as u64) << 12;
+        Some(self.base + offset)
+    }
+}
+
+#[test_case]
+fn test_pci_config_address() {
+    let region = PciConfigRegion { base: PhysAddr::new(0xb000_0000), segment: 0, start_bus: 0, end_bus: 0xff };
+    assert_eq!(region.config_address(0, 0, 0), Some(PhysAddr::new(0xb000_0000)));
+    assert_eq!(region.config_address(1, 2, 3), Some(PhysAddr::new(0xb010_3000)));
+    assert_eq!(region.config_address(0, 32, 0), None);
+
+    let mcfg = Mcfg { regions: alloc::vec![PciConfigRegion { start_bus: 0x10, end_bus: 0x1f, ..region }] };
+    assert_eq!(mcfg.config_address(0, 0x11, 0, 1), Some(PhysAddr::new(0xb010_1000)));
+    assert_eq!(mcfg.config_address(0, 0x20, 0, 0), None);
+    assert_eq!(mcfg.config_address(1, 0x11, 0, 0), None);
+}
+
+#[test_case]
+fn test_mcfg_rejects_bad_base() {
+    let mut table = alloc::vec![0u8; HEADER_SIZE + 8];
+    table.extend_from_slice(&[0, 0, 0, 0xb0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0, 0, 0, 0]);
+    assert_eq!(Mcfg::parse(&table).unwrap().regions[0].base, PhysAddr::new(0xb000_0000));
+
+    // Bits above 51 cannot be part of a physical address
+    table[HEADER_SIZE + 8 + 7] = 0x80;
+    assert_eq!(Mcfg::parse(&table).err(), Some(AcpiError::Malformed(*b"MCFG")));
+}
@@ -0,0 +1,358 @@
+// ACPI tables, found through the RSDP the BIOS leaves in low memory.
+//
+// bootloader 0.9 does not pass the RSDP on, so init scans the EBDA and the BIOS area for it,
+// as the spec describes for legacy boot. All of physical memory is mapped at the physical
+// memory offset and the tables are read through that mapping, after checking the pages are
+// mapped, so a bad pointer makes a table malformed instead of faulting. A table is only used
+// when its checksum is right. Parsed tables are kept for the rest of the kernel to query through
+// tables, print_summary writes what was found to serial.
+
+pub mod madt;
+pub mod fadt;
+pub mod hpet;
+pub mod mcfg;
+
+use core::fmt;
+use alloc::vec::Vec;
+use spin::Once;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::serial_println;
+use crate::memory_management::page_table;
+use madt::{Madt, Polarity, Trigger};
+use fadt::Fadt;
+use hpet::Hpet;
+use mcfg::Mcfg;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const EBDA_POINTER: u64 = 0x40e;
+const BIOS_AREA: (u64, u64) = (0xe_0000, 0x10_0000);
+const HEADER_SIZE: usize = 36;
+// Far more than any real table, a bigger length means the pointer to the table was bad
+const MAX_TABLE_SIZE: usize = 1 << 20;
+
+static ACPI: Once<Acpi> = Once::new();
+
+// What the kernel uses from the ACPI tables
+pub struct Acpi {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    // Every table the RSDT or XSDT lists
+    pub tables: Vec<Table>,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    pub mcfg: Option<Mcfg>,
+    // Why tables that are there were left out
+    pub errors: Vec<AcpiError>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+    pub signature: [u8; 4],
+    pub address: PhysAddr,
+}
+
+// A register location in one of the address spaces ACPI knows
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub address: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    Memory,
+    Io,
+    PciConfig,
+    Other(u8),
+}
+
+impl GenericAddress {
+    fn parse(data: &[u8], offset: usize) -> Option<GenericAddress> {
+        let space = match *data.get(offset)? {
+            0 => AddressSpace::Memory,
+            1 => AddressSpace::Io,
+            2 => AddressSpace::PciConfig,
+            space => AddressSpace::Other(space),
+        };
+        Some(GenericAddress {
+            space,
+            bit_width: *data.get(offset + 1)?,
+            bit_offset: *data.get(offset + 2)?,
+            address: read_u64(data, offset + 4)?,
+        })
+    }
+}
+
+impl fmt::Display for GenericAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.space {
+            AddressSpace::Memory => write!(f, "memory {:#x}", self.address),
+            AddressSpace::Io => write!(f, "port {:#x}", self.address),
+            AddressSpace::PciConfig => write!(f, "PCI config {:#x}", self.address),
+            AddressSpace::Other(space) => write!(f, "space {} {:#x}", space, self.address),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    NoRsdp,
+    BadChecksum([u8; 4]),
+    Malformed([u8; 4]),
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AcpiError::NoRsdp => write!(f, "no RSDP found"),
+            AcpiError::BadChecksum(signature) => write!(f, "{} has a bad checksum", Signature(signature)),
+            AcpiError::Malformed(signature) => write!(f, "{} is malformed", Signature(signature)),
+        }
+    }
+}
+
+// A table signature, printed as the ASCII it normally is
+pub struct Signature<'a>(pub &'a [u8]);
+
+impl fmt::Display for Signature<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &byte in self.0 {
+            let printable = byte.is_ascii_graphic() || byte == b' ';
+            write!(f, "{}", if printable { byte as char } else { '?' })?;
+        }
+        Ok(())
+    }
+}
+
+// Finds and parses the tables. Needs the heap. Tables that are missing or broken are left
+// out, only a missing or broken RSDP or root table is an error.
+pub fn init(phys_offset: VirtAddr) -> Result<&'static Acpi, AcpiError> {
+    let memory = PhysMemory(phys_offset);
+    let rsdp = find_rsdp(memory)?;
+    let revision = rsdp[15];
+    let mut oem_id = [0; 6];
+    oem_id.copy_from_slice(&rsdp[9..15]);
+
+    // ACPI 2.0 and later have the XSDT with 64 bit pointers, which is preferred
+    let xsdt = if revision >= 2 { read_u64(rsdp, 24) } else { None };
+    let (root, entry_size) = match xsdt {
+        Some(xsdt) if xsdt != 0 => {
+            let xsdt = PhysAddr::try_new(xsdt).map_err(|_| AcpiError::Malformed(*b"XSDT"))?;
+            (memory.table(xsdt, *b"XSDT")?, 8)
+        }
+        _ => (memory.table(PhysAddr::new(read_u32(rsdp, 16).unwrap_or(0) as u64), *b"RSDT")?, 4),
+    };
+    let malformed = AcpiError::Malformed(root[..4].try_into().unwrap());
+    // Entries that are not even physical addresses are skipped, ones pointing at memory that
+    // is not there break the root table
+    let tables = root[HEADER_SIZE..].chunks_exact(entry_size)
+        .filter_map(|entry| PhysAddr::try_new(if entry_size == 8 {
+            u64::from_le_bytes(entry.try_into().unwrap())
+        } else {
+            u32::from_le_bytes(entry.try_into().unwrap()) as u64
+        }).ok())
+        .map(|address| {
+            let signature = memory.bytes(address, 4).ok_or(malformed)?.try_into().unwrap();
+            Ok(Table { signature, address })
+        })
+        .collect::<Result<_, _>>()?;
+
+    let mut acpi = Acpi {
+        revision, oem_id, tables,
+        madt: None, fadt: None, hpet: None, mcfg: None,
+        errors: Vec::new(),
+    };
+    acpi.madt = acpi.parse(memory, b"APIC", Madt::parse);
+    acpi.fadt = acpi.parse(memory, b"FACP", Fadt::parse);
+    acpi.hpet = acpi.parse(memory, b"HPET", Hpet::parse);
+    acpi.mcfg = acpi.parse(memory, b"MCFG", Mcfg::parse);
+    Ok(ACPI.call_once(|| acpi))
+}
+
+// The tables, if init found them
+pub fn tables() -> Option<&'static Acpi> {
+    ACPI.r#try()
+}
+
+impl Acpi {
+    // The first table with signature, None when there is none or it is broken. Why it is
+    // broken goes to errors.
+    fn parse<T>(&mut self, memory: PhysMemory, signature: &[u8; 4],
+        parse: fn(&[u8]) -> Result<T, AcpiError>) -> Option<T>
+    {
+        let table = self.tables.iter().find(|table| &table.signature == signature)?;
+        match memory.table(table.address, *signature).and_then(parse) {
+            Ok(parsed) => Some(parsed),
+            Err(err) => {
+                self.errors.push(err);
+                None
+            }
+        }
+    }
+
+    pub fn print_summary(&self) {
+        serial_println!("ACPI revision {}, OEM {}", self.revision, Signature(&self.oem_id));
+        serial_println!("    Tables:");
+        for table in &self.tables {
+            serial_println!("        {} at {:#x}", Signature(&table.signature), table.address.as_u64());
+        }
+        for err in &self.errors {
+            serial_println!("    [err: {}]", err);
+        }
+
+        match &self.madt {
+            Some(madt) => {
+                serial_println!("    MADT: local APIC at {:#x}{}", madt.local_apic_address.as_u64(),
+                    if madt.legacy_pics { ", with 8259 PICs" } else { "" });
+                for cpu in &madt.processors {
+                    serial_println!("        CPU {}: APIC ID {}{}", cpu.uid, cpu.apic_id,
+                        if cpu.enabled { "" } else { ", disabled" });
+                }
+                for io_apic in &madt.io_apics {
+                    serial_println!("        I/O APIC {} at {:#x}, GSIs from {}", io_apic.id,
+                        io_apic.address.as_u64(), io_apic.gsi_base);
+                }
+                for entry in &madt.overrides {
+                    serial_println!("        IRQ {} -> GSI {}, {}, {}", entry.irq, entry.gsi,
+                        match entry.polarity { Polarity::ActiveHigh => "active high", Polarity::ActiveLow => "active low" },
+                        match entry.trigger { Trigger::Edge => "edge", Trigger::Level => "level" });
+                }
+            }
+            None => { serial_println!("    MADT: none"); }
+        }
+
+        match &self.fadt {
+            Some(fadt) => {
+                serial_println!("    FADT: SCI IRQ {}, DSDT at {:#x}", fadt.sci_interrupt, fadt.dsdt.as_u64());
+                serial_println!("        PM1a event {:#x}, control {:#x}", fadt.pm1a_event, fadt.pm1a_control);
+                if let (Some(event), Some(control)) = (fadt.pm1b_event, fadt.pm1b_control) {
+                    serial_println!("        PM1b event {:#x}, control {:#x}", event, control);
+                }
+                if let Some(port) = fadt.pm_timer {
+                    serial_println!("        PM timer {:#x}, {} bit", port, if fadt.pm_timer_32bit { 32 } else { 24 });
+                }
+                if let Some(port) = fadt.smi_command {
+                    serial_println!("        SMI command {:#x}, enable {:#x}, disable {:#x}", port,
+                        fadt.acpi_enable, fadt.acpi_disable);
+                }
+                if let Some((register, value)) = fadt.reset {
+                    serial_println!("        Reset by writing {:#x} to {}", value, register);
+                }
+                match fadt.century {
+                    Some(register) => { serial_println!("        RTC century in CMOS {:#x}", register); }
+                    None => { serial_println!("        No RTC century register"); }
+                }
+                if let Some(has_8042) = fadt.has_8042 {
+                    serial_println!("        8042 keyboard controller {}", if has_8042 { "present" } else { "absent" });
+                }
+            }
+            None => { serial_println!("    FADT: none"); }
+        }
+
+        match &self.hpet {
+            Some(hpet) => {
+                serial_println!("    HPET {}: at {:#x}, {} comparators, {} bit counter, min tick {}, vendor {:#06x}{}",
+                    hpet.number, hpet.address.as_u64(), hpet.comparators, if hpet.counter_64bit { 64 } else { 32 },
+                    hpet.min_tick, hpet.vendor_id, if hpet.legacy_replacement { ", legacy replacement" } else { "" });
+            }
+            None => { serial_println!("    HPET: none"); }
+        }
+
+        match &self.mcfg {
+            Some(mcfg) => {
+                serial_println!("    MCFG:");
+                for region in &mcfg.regions {
+                    serial_println!("        Segment {} buses {:#x}..={:#x} at {:#x}", region.segment,
+                        region.start_bus, region.end_bus, region.base.as_u64());
+                }
+            }
+            None => { serial_println!("    MCFG: none"); }
+        }
+    }
+}
+
+// Physical memory through the bootloader's mapping of all of it
+#[derive(Clone, Copy)]
+struct PhysMemory(VirtAddr);
+
+impl PhysMemory {
+    // None when some of it is not mapped, which a bad pointer in a table can ask for
+    fn bytes(self, addr: PhysAddr, len: usize) -> Option<&'static [u8]> {
+        let virt = self.0.as_u64().checked_add(addr.as_u64())?;
+        if !page_table::is_mapped(virt, len as u64) {
+            return None;
+        }
+        Some(unsafe { core::slice::from_raw_parts(virt as *const u8, len) })
+    }
+
+    // A whole table with header, after checking the checksum over its length. Errors are
+    // reported with the expected signature when the header cannot be read.
+    fn table(self, addr: PhysAddr, expected: [u8; 4]) -> Result<&'static [u8], AcpiError> {
+        let header = self.bytes(addr, HEADER_SIZE).ok_or(AcpiError::Malformed(expected))?;
+        let signature = header[..4].try_into().unwrap();
+        let len = read_u32(header, 4).unwrap() as usize;
+        if !(HEADER_SIZE..=MAX_TABLE_SIZE).contains(&len) {
+            return Err(AcpiError::Malformed(signature));
+        }
+        let table = self.bytes(addr, len).ok_or(AcpiError::Malformed(signature))?;
+        if !checksum_ok(table) {
+            return Err(AcpiError::BadChecksum(signature));
+        }
+        Ok(table)
+    }
+}
+
+// The RSDP is 16 byte aligned in the first KiB of the EBDA or in the BIOS area
+fn find_rsdp(memory: PhysMemory) -> Result<&'static [u8], AcpiError> {
+    let unreadable = AcpiError::Malformed(*b"RSDP");
+    let ebda = (read_u16(memory.bytes(PhysAddr::new(EBDA_POINTER), 2).ok_or(unreadable)?, 0).unwrap() as u64) << 4;
+    let candidates = [(ebda, ebda + 1024), BIOS_AREA].into_iter()
+        .filter(|&(start, _)| start != 0)
+        .flat_map(|(start, end)| (start..end).step_by(16));
+    for addr in candidates {
+        let rsdp = memory.bytes(PhysAddr::new(addr), 36).ok_or(unreadable)?;
+        if rsdp.starts_with(RSDP_SIGNATURE) && rsdp_valid(rsdp) {
+            return Ok(rsdp);
+        }
+    }
+    Err(AcpiError::NoRsdp)
+}
+
+// The first 20 bytes are the ACPI 1.0 RSDP with their own checksum, revision 2 adds 16 more
+// covered by the extended checksum
+fn rsdp_valid(rsdp: &[u8]) -> bool {
+    checksum_ok(&rsdp[..20]) && (rsdp[15] < 2 || checksum_ok(rsdp))
+}
+
+fn checksum_ok(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+#[test_case]
+fn test_acpi_tables() {
+    // QEMU always has ACPI, with a MADT listing the boot CPU and one I/O APIC
+    let acpi = tables().expect("ACPI tables were not found at boot");
+    let madt = acpi.madt.as_ref().expect("no MADT");
+    assert!(madt.processors.iter().any(|processor| processor.enabled));
+    assert!(!madt.io_apics.is_empty());
+    let fadt = acpi.fadt.as_ref().expect("no FADT");
+    assert!(fadt.pm_timer.is_some());
+    assert!(acpi.errors.is_empty(), "{:?}", acpi.errors);
+    assert!(checksum_ok(&[0x10, 0xf0]) && !checksum_ok(&[1]));
+}
@@ -0,0 +1,278 @@
+// The local APIC of the boot CPU and the I/O APICs, which take over from the 8259 PICs.
+//
+// Where the I/O APICs are and how the ISA IRQs are wired to them comes from the ACPI MADT.
+// The local APIC timer replaces the PIT as the source of the timer tick. Its rate is not
+// reported anywhere, so init counts it against PIT ticks while the PICs still deliver them.
+// Only the boot CPU runs, so every interrupt is sent to its local APIC.
+
+use core::{arch::x86_64::__cpuid, fmt};
+use alloc::vec::Vec;
+use spin::{Mutex, Once};
+use x86_64::{PhysAddr, registers::model_specific::Msr};
+use crate::{
+    hardware_interface::{acpi::madt::{Madt, Polarity, Trigger}, interrupts::{InterruptIndex, TIMER_HZ}},
+    memory_management::{mmio::{MmioRegion, map_mmio}, vmm::VmmError},
+    time,
+};
+
+const APIC_FEATURE: u32 = 1 << 9;
+const IA32_APIC_BASE: u32 = 0x1b;
+const GLOBAL_ENABLE: u64 = 1 << 11;
+const BASE_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// Local APIC registers
+const LAPIC_SIZE: usize = 0x400;
+const ID: usize = 0x20;
+const TASK_PRIORITY: usize = 0x80;
+const EOI: usize = 0xb0;
+const SPURIOUS: usize = 0xf0;
+const LVT_TIMER: usize = 0x320;
+const LVT_ERROR: usize = 0x370;
+const TIMER_INITIAL: usize = 0x380;
+const TIMER_CURRENT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3e0;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const PERIODIC: u32 = 1 << 17;
+const DIVIDE_BY_16: u32 = 0b0011;
+const CALIBRATION_TICKS: u64 = 10;
+
+// I/O APIC registers, all reached through the select and window registers
+const IOAPIC_SIZE: usize = 0x20;
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const REDIRECTION_TABLE: u32 = 0x10;
+
+const ACTIVE_LOW: u64 = 1 << 13;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+const ENTRY_MASKED: u64 = 1 << 16;
+
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// The ISA IRQs routed through the I/O APICs, to the vectors they have with the PICs
+const ISA_ROUTES: [(u8, InterruptIndex); 2] = [(1, InterruptIndex::Keyboard), (4, InterruptIndex::Serial)];
+
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+static IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+
+#[derive(Debug)]
+pub enum ApicError {
+    Disabled,
+    NotSupported,
+    NoMadt,
+    NoIoApic,
+    Mmio(VmmError),
+    CalibrationFailed,
+}
+
+impl fmt::Display for ApicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApicError::Disabled => write!(f, "built with legacy_pic"),
+            ApicError::NotSupported => write!(f, "the CPU has no local APIC"),
+            ApicError::NoMadt => write!(f, "no usable ACPI MADT"),
+            ApicError::NoIoApic => write!(f, "the MADT lists no I/O APIC"),
+            ApicError::Mmio(err) => write!(f, "mapping the APIC registers failed: {:?}", err),
+            ApicError::CalibrationFailed => write!(f, "the local APIC timer did not count"),
+        }
+    }
+}
+
+// What init found, for the boot log
+pub struct ApicInfo {
+    pub local_apic_id: u8,
+    pub io_apics: usize,
+    // The local APIC timer's count rate
+    pub timer_hz: u64,
+}
+
+struct LocalApic {
+    registers: MmioRegion,
+    // The timer count of one tick
+    timer_count: u32,
+}
+
+impl LocalApic {
+    fn read(&self, register: usize) -> u32 {
+        self.registers.read(register)
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        self.registers.write(register, value)
+    }
+
+    fn id(&self) -> u8 {
+        (self.read(ID) >> 24) as u8
+    }
+}
+
+struct IoApic {
+    registers: MmioRegion,
+    // The first global system interrupt and how many there are
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        self.registers.write(IOREGSEL, register);
+        self.registers.read(IOWIN)
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        self.registers.write(IOREGSEL, register);
+        self.registers.write(IOWIN, value);
+    }
+
+    #[allow(dead_code)]
+    fn entry(&self, index: u32) -> u64 {
+        let register = REDIRECTION_TABLE + index * 2;
+        self.read(register) as u64 | (self.read(register + 1) as u64) << 32
+    }
+
+    // The high half with the destination goes first, an entry that gets unmasked by the low
+    // half is then complete
+    fn set_entry(&self, index: u32, entry: u64) {
+        let register = REDIRECTION_TABLE + index * 2;
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+}
+
+// Enables the local APIC, measures its timer and maps the I/O APICs with all their entries
+// masked. Needs interrupts enabled and the PIT ticking through the PICs. Nothing arrives
+// through the APICs before start.
+pub fn init(madt: &Madt) -> Result<ApicInfo, ApicError> {
+    if __cpuid(1).edx & APIC_FEATURE == 0 {
+        return Err(ApicError::NotSupported);
+    }
+    if madt.io_apics.is_empty() {
+        return Err(ApicError::NoIoApic);
+    }
+
+    // The MSR has the base the local APIC really uses, the MADT normally agrees
+    let mut apic_base = Msr::new(IA32_APIC_BASE);
+    let base = unsafe { apic_base.read() };
+    let registers = map_mmio(PhysAddr::new(base & BASE_MASK), LAPIC_SIZE).map_err(ApicError::Mmio)?;
+    let mut local_apic = LocalApic { registers, timer_count: 0 };
+    unsafe { apic_base.write(base | GLOBAL_ENABLE) };
+    let spurious = local_apic.read(SPURIOUS);
+    local_apic.write(TASK_PRIORITY, 0);
+    local_apic.write(LVT_TIMER, LVT_MASKED);
+    local_apic.write(LVT_ERROR, LVT_MASKED);
+    local_apic.write(SPURIOUS, SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+
+    let io_apics = match calibrate_and_map(&mut local_apic, madt) {
+        Ok(io_apics) => io_apics,
+        Err(err) => {
+            // The PICs stay in charge, so the local APIC goes back to how it was found.
+            // Its registers are gone once the MSR disables it, so they go first.
+            local_apic.write(SPURIOUS, spurious);
+            unsafe { apic_base.write(base) };
+            return Err(err);
+        }
+    };
+
+    let info = ApicInfo {
+        local_apic_id: local_apic.id(),
+        io_apics: io_apics.len(),
+        timer_hz: local_apic.timer_count as u64 * TIMER_HZ,
+    };
+    *IO_APICS.lock() = io_apics;
+    LOCAL_APIC.call_once(|| local_apic);
+    Ok(info)
+}
+
+// Measures the local APIC timer and maps the I/O APICs with all their entries masked
+fn calibrate_and_map(local_apic: &mut LocalApic, madt: &Madt) -> Result<Vec<IoApic>, ApicError> {
+    local_apic.timer_count = calibrate_timer(local_apic).ok_or(ApicError::CalibrationFailed)?;
+
+    let mut io_apics = Vec::new();
+    for entry in &madt.io_apics {
+        let registers = map_mmio(entry.address, IOAPIC_SIZE).map_err(ApicError::Mmio)?;
+        let mut io_apic = IoApic { registers, gsi_base: entry.gsi_base, entries: 0 };
+        io_apic.entries = (io_apic.read(IOAPIC_VERSION) >> 16 & 0xff) + 1;
+        for index in 0..io_apic.entries {
+            io_apic.set_entry(index, ENTRY_MASKED);
+        }
+        io_apics.push(io_apic);
+    }
+    Ok(io_apics)
+}
+
+// Routes the ISA IRQs and starts the local APIC timer in place of the PIT. The PICs have to
+// be masked already and interrupts disabled until the switch is complete.
+pub fn start(madt: &Madt) {
+    let local_apic = LOCAL_APIC.r#try().expect("[err: apic::start before apic::init]");
+    let io_apics = IO_APICS.lock();
+    for (irq, index) in ISA_ROUTES {
+        let (gsi, polarity, trigger) = madt.isa_irq(irq);
+        // An IRQ no I/O APIC handles stays without interrupts, like a masked PIC line
+        if let Some(io_apic) = io_apics.iter().find(|io_apic| io_apic.handles(gsi)) {
+            io_apic.set_entry(gsi - io_apic.gsi_base,
+                redirection_entry(index as u8, polarity, trigger, local_apic.id()));
+        }
+    }
+
+    local_apic.write(TIMER_DIVIDE, DIVIDE_BY_16);
+    local_apic.write(LVT_TIMER, PERIODIC | InterruptIndex::Timer as u32);
+    local_apic.write(TIMER_INITIAL, local_apic.timer_count);
+}
+
+pub fn end_of_interrupt() {
+    if let Some(local_apic) = LOCAL_APIC.r#try() {
+        local_apic.write(EOI, 0);
+    }
+}
+
+// Counts how far the timer runs in one tick. Starts right after a tick, so the count covers
+// whole ticks.
+fn calibrate_timer(local_apic: &LocalApic) -> Option<u32> {
+    local_apic.write(TIMER_DIVIDE, DIVIDE_BY_16);
+    time::sleep_ticks(1);
+    local_apic.write(TIMER_INITIAL, u32::MAX);
+    time::sleep_ticks(CALIBRATION_TICKS);
+    let remaining = local_apic.read(TIMER_CURRENT);
+    local_apic.write(TIMER_INITIAL, 0);
+
+    // Having run down to 0 means the timer is too fast to measure this way
+    let count = (u32::MAX - remaining) as u64 / CALIBRATION_TICKS;
+    (remaining != 0 && count != 0).then_some(count as u32)
+}
+
+// Fixed delivery in physical destination mode
+fn redirection_entry(vector: u8, polarity: Polarity, trigger: Trigger, destination: u8) -> u64 {
+    let mut entry = vector as u64 | (destination as u64) << 56;
+    if polarity == Polarity::ActiveLow {
+        entry |= ACTIVE_LOW;
+    }
+    if trigger == Trigger::Level {
+        entry |= LEVEL_TRIGGERED;
+    }
+    entry
+}
+
+#[test_case]
+fn test_apic_routing() {
+    assert_eq!(redirection_entry(33, Polarity::ActiveHigh, Trigger::Edge, 0), 33);
+    assert_eq!(redirection_entry(36, Polarity::ActiveLow, Trigger::Level, 2), 36 | 1 << 13 | 1 << 15 | 2 << 56);
+
+    // Booted on the APICs, the keyboard is routed to its PIC vector and nothing else masked
+    let (Some(local_apic), Some(acpi)) = (LOCAL_APIC.r#try(), crate::hardware_interface::acpi::tables()) else {
+        return;
+    };
+    let madt = acpi.madt.as_ref().unwrap();
+    let (gsi, _, _) = madt.isa_irq(1);
+    let io_apics = IO_APICS.lock();
+    let io_apic = io_apics.iter().find(|io_apic| io_apic.handles(gsi)).unwrap();
+    let entry = io_apic.entry(gsi - io_apic.gsi_base);
+    assert_eq!(entry & 0xff, InterruptIndex::Keyboard as u64);
+    assert_eq!(entry & ENTRY_MASKED, 0);
+    assert_eq!((entry >> 56) as u8, local_apic.id());
+}
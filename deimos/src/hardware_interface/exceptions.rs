@@ -0,0 +1,371 @@
+// Handlers for the CPU exceptions, vectors 0 to 31.
+//
+// Every handler dumps the exception, its decoded error code, the interrupt stack frame and
+// the control registers through kprintln, so the dump shows up on VGA and serial even when
+// the interrupted code holds one of their locks. Exceptions the kernel can not recover from
+// panic after the dump.
+//
+// Debug and breakpoint exceptions enter through trap_entry! stubs instead of the
+// x86-interrupt ABI, which save every general purpose register in a TrapFrame so a debugger
+// can read and change them before execution continues.
+
+use core::fmt;
+use x86_64::{
+    VirtAddr,
+    registers::{control::{Cr0, Cr2, Cr3, Cr4}, model_specific::Efer, rflags::RFlags},
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+};
+use super::gdt;
+use crate::{kprintln, debugging::{backtrace, monitor, symbols::Symbolized}, memory_management::{stack, vmm}};
+#[cfg(feature = "gdb_stub")]
+use crate::debugging::gdb;
+
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available.set_handler_fn(device_not_available_handler);
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+    idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.cp_protection_exception.set_handler_fn(control_protection_handler);
+    idt.hv_injection_exception.set_handler_fn(hv_injection_handler);
+    idt.vmm_communication_exception.set_handler_fn(vmm_communication_handler);
+    idt.security_exception.set_handler_fn(security_exception_handler);
+    unsafe {
+        idt.debug.set_handler_addr(VirtAddr::new(debug_entry as *const () as u64));
+        idt.breakpoint.set_handler_addr(VirtAddr::new(breakpoint_entry as *const () as u64));
+        idt.double_fault.set_handler_fn(double_fault_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.non_maskable_interrupt.set_handler_fn(nmi_handler)
+            .set_stack_index(gdt::NMI_IST_INDEX);
+        idt.machine_check.set_handler_fn(machine_check_handler)
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+    }
+    #[cfg(not(feature = "page_fault_ist"))]
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    #[cfg(feature = "page_fault_ist")]
+    unsafe {
+        idt.page_fault.set_handler_fn(page_fault_handler)
+            .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+    }
+}
+
+// The registers of the interrupted code, in the order trap_entry! pushes them, followed by
+// the frame the CPU pushed. Changes are restored when the handler returns.
+#[repr(C)]
+pub struct TrapFrame {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub stack_frame: InterruptStackFrame,
+}
+
+// An IDT entry point for exceptions without an error code that passes a TrapFrame to
+// handler. The CPU leaves the stack 8 bytes off 16 byte alignment, the 15 pushes fix that
+// up for the call.
+macro_rules! trap_entry {
+    ($entry:ident, $handler:ident) => {
+        #[unsafe(naked)]
+        extern "C" fn $entry() -> ! {
+            core::arch::naked_asm!(
+                "push r15", "push r14", "push r13", "push r12",
+                "push r11", "push r10", "push r9", "push r8",
+                "push rbp", "push rdi", "push rsi", "push rdx",
+                "push rcx", "push rbx", "push rax",
+                "cld",
+                "mov rdi, rsp",
+                "call {handler}",
+                "pop rax", "pop rbx", "pop rcx", "pop rdx",
+                "pop rsi", "pop rdi", "pop rbp", "pop r8",
+                "pop r9", "pop r10", "pop r11", "pop r12",
+                "pop r13", "pop r14", "pop r15",
+                "iretq",
+                handler = sym $handler,
+            )
+        }
+    };
+}
+
+enum ErrorCode {
+    None,
+    Raw(u64),
+    Selector(u64),
+    PageFault(PageFaultErrorCode),
+}
+
+fn dump(name: &str, vector: u8, error_code: ErrorCode, stack_frame: &InterruptStackFrame) {
+    kprintln!("EXCEPTION: {} (vector {})", name, vector);
+    match error_code {
+        ErrorCode::None => {}
+        ErrorCode::Raw(code) => kprintln!("Error code: {:#x}", code),
+        ErrorCode::Selector(code) => kprintln!("Error code: {:#x} ({})", code, SelectorErrorCode(code)),
+        ErrorCode::PageFault(code) => {
+            kprintln!("Error code: {:?}", code);
+            kprintln!("Accessed address: {:#x}", Cr2::read_raw());
+        }
+    }
+    kprintln!("{:#?}", stack_frame);
+    kprintln!("At {}", Symbolized(stack_frame.instruction_pointer.as_u64()));
+    kprintln!("CR0 {:#x}  CR2 {:#x}  CR3 {:#x}", Cr0::read_raw(), Cr2::read_raw(),
+        Cr3::read().0.start_address().as_u64());
+    kprintln!("CR4 {:#x}  EFER {:#x}", Cr4::read_raw(), Efer::read_raw());
+}
+
+// The error code of #TS, #NP, #SS and #GP names the segment selector or IDT gate at fault
+struct SelectorErrorCode(u64);
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "not caused by a selector");
+        }
+        let table = match (self.0 >> 1) & 0b11 {
+            0b00 => "GDT",
+            0b10 => "LDT",
+            _ => "IDT",
+        };
+        write!(f, "{} index {}", table, (self.0 >> 3) & 0x1fff)?;
+        if self.0 & 1 != 0 {
+            write!(f, ", external event")?;
+        }
+        Ok(())
+    }
+}
+
+// Handlers that dump the exception and panic, with or without an error code
+macro_rules! fatal_handler {
+    ($handler:ident, $vector:expr, $name:expr) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame) {
+            dump($name, $vector, ErrorCode::None, &stack_frame);
+            panic!("EXCEPTION: {}", $name);
+        }
+    };
+    ($handler:ident, $vector:expr, $name:expr, $error_code:ident) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame, error_code: u64) {
+            dump($name, $vector, ErrorCode::$error_code(error_code), &stack_frame);
+            panic!("EXCEPTION: {}", $name);
+        }
+    };
+}
+
+fatal_handler!(divide_error_handler, 0, "DIVIDE ERROR (#DE)");
+fatal_handler!(overflow_handler, 4, "OVERFLOW (#OF)");
+fatal_handler!(bound_range_exceeded_handler, 5, "BOUND RANGE EXCEEDED (#BR)");
+fatal_handler!(invalid_opcode_handler, 6, "INVALID OPCODE (#UD)");
+fatal_handler!(device_not_available_handler, 7, "DEVICE NOT AVAILABLE (#NM)");
+fatal_handler!(invalid_tss_handler, 10, "INVALID TSS (#TS)", Selector);
+fatal_handler!(segment_not_present_handler, 11, "SEGMENT NOT PRESENT (#NP)", Selector);
+fatal_handler!(stack_segment_fault_handler, 12, "STACK SEGMENT FAULT (#SS)", Selector);
+fatal_handler!(general_protection_fault_handler, 13, "GENERAL PROTECTION FAULT (#GP)", Selector);
+fatal_handler!(x87_floating_point_handler, 16, "x87 FLOATING POINT (#MF)");
+fatal_handler!(alignment_check_handler, 17, "ALIGNMENT CHECK (#AC)", Raw);
+fatal_handler!(simd_floating_point_handler, 19, "SIMD FLOATING POINT (#XM)");
+fatal_handler!(virtualization_handler, 20, "VIRTUALIZATION (#VE)");
+fatal_handler!(control_protection_handler, 21, "CONTROL PROTECTION (#CP)", Raw);
+fatal_handler!(hv_injection_handler, 28, "HYPERVISOR INJECTION (#HV)");
+fatal_handler!(vmm_communication_handler, 29, "VMM COMMUNICATION (#VC)", Raw);
+fatal_handler!(security_exception_handler, 30, "SECURITY (#SX)", Raw);
+
+trap_entry!(debug_entry, debug_handler);
+trap_entry!(breakpoint_entry, breakpoint_handler);
+
+// Single steps and breakpoints belong to the GDB stub when there is one. Without it single
+// steps are reported and execution carries on with the trap flag cleared, or every
+// instruction would trap again. Breakpoints stop in the kernel monitor, except in tests
+// which have nobody to type at it.
+extern "C" fn debug_handler(frame: &mut TrapFrame) {
+    #[cfg(feature = "gdb_stub")]
+    if gdb::handle_trap(1, frame) {
+        return;
+    }
+    dump("DEBUG (#DB)", 1, ErrorCode::None, &frame.stack_frame);
+    unsafe {
+        frame.stack_frame.as_mut().update(|stack_frame| stack_frame.cpu_flags &= !RFlags::TRAP_FLAG.bits());
+    }
+}
+
+extern "C" fn breakpoint_handler(frame: &mut TrapFrame) {
+    #[cfg(feature = "gdb_stub")]
+    if gdb::handle_trap(3, frame) {
+        return;
+    }
+    dump("BREAKPOINT (#BP)", 3, ErrorCode::None, &frame.stack_frame);
+    if cfg!(not(test)) {
+        monitor::run(monitor::Stop::Breakpoint(frame));
+    }
+}
+
+extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) -> ! {
+    let frame_pointer = backtrace::frame_pointer();
+    dump("DOUBLE FAULT (#DF)", 8, ErrorCode::Raw(error_code), &stack_frame);
+    backtrace::print_interrupted(stack_frame.instruction_pointer, frame_pointer);
+    // An overflow faults on the guard page, and pushing the page fault's frame onto that
+    // same page escalates it to a double fault, with CR2 still pointing into the guard
+    if let Some(stack) = stack::guard_hit(Cr2::read()) {
+        panic!("EXCEPTION: DOUBLE FAULT: kernel stack overflow in {}", stack);
+    }
+    panic!("EXCEPTION: DOUBLE FAULT (#DF)");
+}
+
+// NMIs come from hardware errors or watchdogs and can hit anywhere, even in other handlers,
+// so this only reports what the legacy system control port says and carries on
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    use x86_64::instructions::port::PortReadOnly;
+    let control_b: u8 = unsafe { PortReadOnly::new(0x61).read() };
+    dump("NON-MASKABLE INTERRUPT", 2, ErrorCode::None, &stack_frame);
+    if control_b & (1 << 7) != 0 {
+        kprintln!("Memory parity error");
+    }
+    if control_b & (1 << 6) != 0 {
+        kprintln!("I/O channel check");
+    }
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    use x86_64::registers::model_specific::Msr;
+    const IA32_MCG_CAP: u32 = 0x179;
+    const IA32_MCG_STATUS: u32 = 0x17a;
+    const IA32_MC0_STATUS: u32 = 0x401; // each bank has 4 MSRs: CTL, STATUS, ADDR, MISC
+
+    dump("MACHINE CHECK (#MC)", 18, ErrorCode::None, &stack_frame);
+    let banks = unsafe { Msr::new(IA32_MCG_CAP).read() } & 0xff;
+    kprintln!("MCG_STATUS: {:#x}", unsafe { Msr::new(IA32_MCG_STATUS).read() });
+    for bank in 0..banks as u32 {
+        let status = unsafe { Msr::new(IA32_MC0_STATUS + bank * 4).read() };
+        if status & (1 << 63) != 0 {
+            kprintln!("Bank {}: status {:#x}", bank, status);
+        }
+    }
+    panic!("EXCEPTION: MACHINE CHECK (#MC)");
+}
+
+// Called for page faults nothing else could resolve, once there are tasks to blame.
+// Kills the current task and switches away without returning. Returns when the fault did
+// not happen in a task, which leaves the kernel to panic.
+pub type OopsHandler = fn(&InterruptStackFrame, VirtAddr, PageFaultErrorCode);
+
+static OOPS_HANDLER: spin::Mutex<Option<OopsHandler>> = spin::Mutex::new(None);
+
+#[allow(dead_code)]
+pub fn set_oops_handler(handler: OopsHandler) {
+    *OOPS_HANDLER.lock() = Some(handler);
+}
+
+// Page faults go through a policy: demand faults on lazy regions are resolved and the access
+// retried, stack overflows and everything else are reported, then the current task is killed
+// through the oops handler if there is one, and the kernel panics if there is not
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode) {
+    let frame_pointer = backtrace::frame_pointer();
+    let addr = Cr2::read();
+    if vmm::handle_demand_fault(addr, error_code) {
+        return;
+    }
+
+    dump("PAGE FAULT (#PF)", 14, ErrorCode::PageFault(error_code), &stack_frame);
+    backtrace::print_interrupted(stack_frame.instruction_pointer, frame_pointer);
+    if let Some(stack) = stack::guard_hit(addr) {
+        panic!("EXCEPTION: PAGE FAULT: kernel stack overflow in {}", stack);
+    }
+    let oops = OOPS_HANDLER.try_lock().and_then(|handler| *handler);
+    if let Some(oops) = oops {
+        oops(&stack_frame, addr, error_code);
+    }
+    panic!("EXCEPTION: PAGE FAULT: {}", PageFaultCause(addr, error_code));
+}
+
+// A one line explanation of a page fault, like "write to unmapped address 0xdeadbeef"
+struct PageFaultCause(VirtAddr, PageFaultErrorCode);
+
+impl fmt::Display for PageFaultCause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let PageFaultCause(addr, code) = *self;
+        let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch from"
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write to"
+        } else {
+            "read from"
+        };
+        let page = if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            "page with reserved bits set at"
+        } else if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "protected page at"
+        } else {
+            "unmapped address"
+        };
+        write!(f, "{} {} {:#x}", access, page, addr.as_u64())?;
+        if code.contains(PageFaultErrorCode::USER_MODE) {
+            write!(f, " in user mode")?;
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_selector_error_code() {
+    use alloc::format;
+    assert_eq!(format!("{}", SelectorErrorCode(0)), "not caused by a selector");
+    assert_eq!(format!("{}", SelectorErrorCode(5 << 3)), "GDT index 5");
+    assert_eq!(format!("{}", SelectorErrorCode(13 << 3 | 0b010)), "IDT index 13");
+    assert_eq!(format!("{}", SelectorErrorCode(2 << 3 | 0b100 | 1)), "LDT index 2, external event");
+}
+
+#[test_case]
+fn test_page_fault_cause() {
+    use alloc::format;
+    let addr = VirtAddr::new(0xdeadb000);
+    let write = PageFaultErrorCode::CAUSED_BY_WRITE;
+    assert_eq!(format!("{}", PageFaultCause(addr, write)), "write to unmapped address 0xdeadb000");
+    let fetch = PageFaultErrorCode::INSTRUCTION_FETCH | PageFaultErrorCode::PROTECTION_VIOLATION;
+    assert_eq!(format!("{}", PageFaultCause(addr, fetch)), "instruction fetch from protected page at 0xdeadb000");
+}
+
+// Replaces the old test that wrote to 0xdeadbeef and never came back: a fault on a page of a
+// lazy region goes through page_fault_handler, gets a zeroed frame and the access is retried
+#[test_case]
+fn test_page_fault_demand_zero() {
+    use x86_64::structures::paging::PageTableFlags;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let page = vmm::VMM.lock().allocate_lazy("page fault test", 4096, flags).expect("vmm allocation failed");
+    let ptr = (page + 8u64).as_mut_ptr::<u64>();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+    assert_eq!(vmm::VMM.lock().unmap(page).unwrap(), 1);
+}
+
+// Replaces the old test that overflowed the stack for real: the address an overflow faults
+// on is in the guard page, which the handlers name before panicking
+#[test_case]
+fn test_stack_overflow_is_recognized() {
+    use alloc::format;
+    let stack = stack::allocate("overflow test", 4096).expect("stack allocation failed");
+    let overflow = stack.bottom() - 8u64;
+    assert_eq!(stack::guard_hit(overflow), Some("overflow test"));
+    assert_eq!(stack::guard_hit(stack.bottom()), None);
+    let cause = PageFaultCause(overflow, PageFaultErrorCode::CAUSED_BY_WRITE);
+    assert_eq!(format!("{}", cause), format!("write to unmapped address {:#x}", overflow.as_u64()));
+}
@@ -1,34 +1,41 @@
 use lazy_static::lazy_static;
+use crate::memory_management::{stack, vmm::VmmError};
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+pub const NMI_IST_INDEX: u16 = 2;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 3;
+
+// Every exception with its own stack, and the name its stack goes by in the VMM
+const IST_STACKS: [(u16, &str); 4] = [
+    (DOUBLE_FAULT_IST_INDEX, "double fault stack"),
+    (PAGE_FAULT_IST_INDEX, "page fault stack"),
+    (NMI_IST_INDEX, "nmi stack"),
+    (MACHINE_CHECK_IST_INDEX, "machine check stack"),
+];
 
 struct Selectors {
     code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            stack_start + STACK_SIZE
-        };
-        tss
-    };
-}
+// The IST exceptions need stacks before the VMM exists, so they start out on these
+// and move to guarded stacks in init_stacks
+const EARLY_STACK_SIZE: usize = 4096 * 5;
+static mut EARLY_STACKS: [[u8; EARLY_STACK_SIZE]; IST_STACKS.len()] = [[0; EARLY_STACK_SIZE]; IST_STACKS.len()];
+
+// The CPU reads the IST entries from here on every interrupt, so init_stacks can swap them
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss = &raw const TSS;
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*tss }));
         (gdt, Selectors { code_selector, tss_selector })
     };
 }
@@ -36,8 +43,11 @@ lazy_static! {
 pub fn init() {
     use x86_64::instructions::tables::load_tss;
     use x86_64::instructions::segmentation::{CS, Segment};
+    for (i, &(index, _)) in IST_STACKS.iter().enumerate() {
+        let early_stack = VirtAddr::from_ptr(unsafe { &raw const EARLY_STACKS[i] }) + EARLY_STACK_SIZE;
+        set_ist_stack(index, early_stack);
+    }
     GDT.0.load();
-            
 
     unsafe {
         CS::set_reg(GDT.1.code_selector);
@@ -45,3 +55,34 @@ pub fn init() {
     }
 }
 
+// Moves the IST entries onto guarded stacks from the VMM.
+// Needs memory management to be initialized.
+pub fn init_stacks() -> Result<(), VmmError> {
+    for (index, name) in IST_STACKS {
+        let stack = stack::allocate(name, stack::KERNEL_STACK_SIZE)?;
+        set_ist_stack(index, stack.leak());
+    }
+    Ok(())
+}
+
+fn set_ist_stack(index: u16, top: VirtAddr) {
+    let tss = &raw mut TSS;
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        (*tss).interrupt_stack_table[index as usize] = top;
+    });
+}
+
+#[test_case]
+fn test_ist_stacks_are_guarded() {
+    use crate::memory_management::vmm::VMM;
+
+    let vmm = VMM.lock();
+    let tss = &raw const TSS;
+    for (index, name) in IST_STACKS {
+        let top = unsafe { (*tss).interrupt_stack_table[index as usize] };
+        let region = vmm.find(top - 1u64).expect("IST stack not allocated from the VMM");
+        assert_eq!(region.name, name);
+        let guard = vmm.find(region.start - 1u64).expect("IST stack has no guard page");
+        assert_eq!(guard.name, name);
+    }
+}
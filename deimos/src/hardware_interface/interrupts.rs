@@ -1,36 +1,47 @@
-use crate::{print, println};
-use super::gdt;
+use crate::print;
+use super::{acpi, apic::{self, ApicError}, exceptions, serial};
+use crate::{debugging::monitor, time};
+use core::sync::atomic::{AtomicBool, Ordering};
 use pic8259::ChainedPics;
-use x86_64::{instructions::hlt, structures::idt::{
+use x86_64::structures::idt::{
     InterruptDescriptorTable, 
-    InterruptStackFrame, PageFaultErrorCode
-}};
+    InterruptStackFrame
+};
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-const TIMER_HZ: u64 = 100;
+pub const TIMER_HZ: u64 = 100;
+// The input clock of all PIT channels
+pub const PIT_HZ: u64 = 1193182;
 
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe {
         ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)
     });
 
+// Set once the APICs took over from the PICs
+static APIC_ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Controller {
+    Pic,
+    Apic,
+}
 
 use lazy_static::lazy_static;
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt.double_fault.set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
+        exceptions::install(&mut idt);
         idt[InterruptIndex::Timer.as_usize()]
             .set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()]
             .set_handler_fn(keyboard_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt[InterruptIndex::Serial.as_usize()]
+            .set_handler_fn(serial_interrupt_handler);
+        idt[apic::SPURIOUS_VECTOR as usize]
+            .set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
@@ -40,8 +51,36 @@ pub fn init_idt() {
     set_timer_pace();
 }
 
+// Switches from the PICs to the local APIC and I/O APICs when the CPU and the MADT have them.
+// The PICs stay in charge if this fails or the kernel was built with legacy_pic.
+pub fn enable_apic() -> Result<apic::ApicInfo, ApicError> {
+    if cfg!(feature = "legacy_pic") {
+        return Err(ApicError::Disabled);
+    }
+    let madt = acpi::tables().and_then(|acpi| acpi.madt.as_ref()).ok_or(ApicError::NoMadt)?;
+    let info = apic::init(madt)?;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        unsafe { PICS.lock().disable() };
+        apic::start(madt);
+        APIC_ENABLED.store(true, Ordering::Relaxed);
+    });
+    Ok(info)
+}
+
+// Which controller delivers the hardware interrupts
+pub fn controller() -> Controller {
+    if APIC_ENABLED.load(Ordering::Relaxed) { Controller::Apic } else { Controller::Pic }
+}
+
+pub fn end_of_interrupt(index: InterruptIndex) {
+    match controller() {
+        Controller::Apic => apic::end_of_interrupt(),
+        Controller::Pic => unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) },
+    }
+}
+
 fn set_timer_pace() {
-    let devisor: u16 = (1193182/TIMER_HZ) as u16;
+    let devisor: u16 = (PIT_HZ/TIMER_HZ) as u16;
     let bytes = devisor.to_le_bytes();
 
     use x86_64::instructions::port::Port;
@@ -54,82 +93,103 @@ fn set_timer_pace() {
     }
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
+extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    time::tick();
 
-extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) -> ! {
-    panic!("EXCEPTION WITH ERROR CODE {:?}: DOUBLE FAULT\n{:#?}", error_code, stack_frame);
+    end_of_interrupt(InterruptIndex::Timer);
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode) {
-    use x86_64::registers::control::Cr2;
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code:       {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt();
+use spin::Mutex;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, ScancodeSet1};
+lazy_static! {
+    static ref KEYBOARD: Mutex<KeyboardState> =
+        Mutex::new(KeyboardState {
+            keyboard: Keyboard::new(ScancodeSet1::new(), layouts::Us104Key, HandleControl::Ignore),
+            ctrl: [false; 2],
+            shift: [false; 2],
+        });
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    //print!(".");
+// pc-keyboard 0.7 keeps its modifier state to itself, so the left and right Ctrl and Shift
+// keys the hotkey needs are tracked here from the key events
+struct KeyboardState {
+    keyboard: Keyboard<layouts::Us104Key, ScancodeSet1>,
+    ctrl: [bool; 2],
+    shift: [bool; 2],
+}
 
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+impl KeyboardState {
+    fn add_byte(&mut self, scancode: u8) -> Option<DecodedKey> {
+        let key_event = self.keyboard.add_byte(scancode).ok()??;
+        let down = key_event.state == KeyState::Down;
+        match key_event.code {
+            KeyCode::LControl => self.ctrl[0] = down,
+            KeyCode::RControl => self.ctrl[1] = down,
+            KeyCode::LShift => self.shift[0] = down,
+            KeyCode::RShift => self.shift[1] = down,
+            _ => {}
+        }
+        self.keyboard.process_keyevent(key_event)
     }
-}
 
-use spin::Mutex;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-lazy_static! {
-    static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-        Mutex::new(Keyboard::new(ScancodeSet1::new(),
-            layouts::Us104Key, HandleControl::Ignore)
-        );
+    // Ctrl+Shift+F12
+    fn is_monitor_hotkey(&self, key: DecodedKey) -> bool {
+        key == DecodedKey::RawKey(KeyCode::F12) && self.ctrl.contains(&true) && self.shift.contains(&true)
+    }
 }
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
     use x86_64::instructions::port::Port;
     
     let mut keyboard = KEYBOARD.lock();
     let mut port = Port::new(0x60);
-
+    let mut enter_monitor = false;
 
     let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(_) => {}, 
-            }
+    if let Some(key) = keyboard.add_byte(scancode) {
+        match key {
+            _ if keyboard.is_monitor_hotkey(key) => enter_monitor = true,
+            DecodedKey::Unicode(character) => print!("{}", character),
+            DecodedKey::RawKey(_) => {}, 
         }
     } 
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+    drop(keyboard);
+    end_of_interrupt(InterruptIndex::Keyboard);
+    if enter_monitor {
+        monitor::run(monitor::Stop::Hotkey(&stack_frame));
     }
 }
 
-#[test_case]
-fn test_breakpoint_exception() {
-    x86_64::instructions::interrupts::int3();
+// Echoes what arrives on COM1, like keyboard input
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    while let Some(byte) = serial::try_receive() {
+        print!("{}", if byte == b'\r' { '\n' } else { byte as char });
+    }
+    end_of_interrupt(InterruptIndex::Serial);
 }
 
-// TODO: This test will fail, figure out how to make unit tests that fail
-#[test_case]
-fn trigger_page_fault() {     unsafe {
-        *(0xdeadbeef as *mut u8) = 42;
-    };
+// The local APIC sends these when an interrupt goes away before it is delivered. They are not
+// acknowledged.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+// The next key pressed, for code that runs with interrupts disabled and has to poll.
+// Shares the keyboard state with the interrupt handler, so modifiers stay in sync.
+pub fn poll_keyboard() -> Option<DecodedKey> {
+    use x86_64::instructions::port::Port;
+    const OUTPUT_FULL: u8 = 1;
+    const MOUSE_DATA: u8 = 1 << 5;
+
+    let mut keyboard = KEYBOARD.try_lock()?;
+    let status: u8 = unsafe { Port::new(0x64).read() };
+    if status & OUTPUT_FULL == 0 || status & MOUSE_DATA != 0 {
+        return None;
+    }
+    let scancode: u8 = unsafe { Port::new(0x60).read() };
+    keyboard.add_byte(scancode)
 }
-// TODO: This test will fail, figure out how to make unit tests that fail
+
 #[test_case]
-fn test_stack_overflow() {
-    #[allow(unconditional_recursion)]
-    fn overflow() {
-        overflow();
-    }
-    overflow();
-    volatile::Volatile::new(0).read(); // ensures optimization wont remove the function calls;
+fn test_breakpoint_exception() {
+    x86_64::instructions::interrupts::int3();
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -137,6 +197,7 @@ fn test_stack_overflow() {
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    Serial = PIC_1_OFFSET + 4,
 }
 
 impl InterruptIndex {
@@ -1,5 +1,9 @@
 pub mod vga_buffer;
 pub mod gdt; 
 pub mod interrupts; 
+pub mod exceptions;
+pub mod console;
 pub mod serial;
-
+pub mod rtc;
+pub mod acpi;
+pub mod apic;
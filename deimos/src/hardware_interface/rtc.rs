@@ -0,0 +1,172 @@
+// The CMOS real time clock, read at boot to know the date and time.
+//
+// The RTC keeps the date either in BCD or binary and the hour in 12 or 24 hour format, as
+// status register B says. It is read until two reads with no update in progress between them
+// agree, so an update can not tear the result. QEMU and most PCs keep the RTC in UTC, which
+// is what this assumes. After boot the wall clock runs on the timer ticks instead.
+
+use core::{fmt, sync::atomic::{AtomicU64, Ordering}, time::Duration};
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+use crate::time;
+use super::acpi;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+// Not standard, but where nearly every RTC keeps it. Used when there is no FADT to say.
+const DEFAULT_CENTURY: u8 = 0x32;
+
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const HOUR_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+const PM: u8 = 1 << 7;
+
+// The index and data port, one register access has to use both without interruption
+static CMOS: Mutex<(Port<u8>, Port<u8>)> = Mutex::new((Port::new(0x70), Port::new(0x71)));
+
+// The Unix time at tick 0
+static BOOT_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    // Seconds since 1970-01-01 00:00:00 UTC
+    pub fn timestamp(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        let seconds = self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        (days * 86400 + seconds) as u64
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+// Reads the RTC and sets the wall clock from it. Returns the date for the boot banner.
+pub fn init() -> DateTime {
+    let now = read();
+    let since_boot = time::uptime().as_secs();
+    BOOT_TIMESTAMP.store(now.timestamp().saturating_sub(since_boot), Ordering::Relaxed);
+    now
+}
+
+// The current Unix time, from the RTC at boot and the timer ticks since
+#[allow(dead_code)]
+pub fn wall_clock() -> Duration {
+    Duration::from_secs(BOOT_TIMESTAMP.load(Ordering::Relaxed)) + time::uptime()
+}
+
+// The date and time the RTC holds right now
+pub fn read() -> DateTime {
+    let mut registers = read_registers();
+    loop {
+        let again = read_registers();
+        if again == registers {
+            break;
+        }
+        registers = again;
+    }
+    let [second, minute, hour, day, month, year, century] = registers;
+
+    let status_b = read_register(STATUS_B);
+    let decode = |value: u8| if status_b & BINARY != 0 { value } else { from_bcd(value) };
+    let mut hour_24 = decode(hour & !PM);
+    if status_b & HOUR_24 == 0 {
+        // 12 AM is midnight and 12 PM noon
+        hour_24 %= 12;
+        if hour & PM != 0 {
+            hour_24 += 12;
+        }
+    }
+    let century = match decode(century) {
+        century @ 19..=21 => century as u16,
+        _ => 20,
+    };
+    DateTime {
+        year: century * 100 + decode(year) as u16,
+        month: decode(month),
+        day: decode(day),
+        hour: hour_24,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+// The raw time registers, taken while no update is in progress
+fn read_registers() -> [u8; 7] {
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    let [second, minute, hour, day, month, year] = [SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR].map(read_register);
+    [second, minute, hour, day, month, year, century_register().map_or(0, read_register)]
+}
+
+// None when the FADT says the RTC keeps no century
+fn century_register() -> Option<u8> {
+    match acpi::tables().and_then(|acpi| acpi.fadt.as_ref()) {
+        Some(fadt) => fadt.century,
+        None => Some(DEFAULT_CENTURY),
+    }
+}
+
+fn read_register(register: u8) -> u8 {
+    interrupts::without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let (index, data) = &mut *cmos;
+        unsafe {
+            index.write(register);
+            data.read()
+        }
+    })
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+// Days since 1970-01-01 of a date in the proleptic Gregorian calendar. Counts years from
+// March, so the leap day is the last day of the year.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[test_case]
+fn test_rtc_timestamp() {
+    let date = |year, month, day, hour, minute, second| DateTime { year, month, day, hour, minute, second };
+    assert_eq!(date(1970, 1, 1, 0, 0, 0).timestamp(), 0);
+    assert_eq!(date(2000, 3, 1, 0, 0, 0).timestamp(), 951868800);
+    assert_eq!(date(2024, 2, 29, 12, 0, 0).timestamp(), 1709208000);
+    assert_eq!(from_bcd(0x59), 59);
+}
+
+#[test_case]
+fn test_rtc_read() {
+    let now = read();
+    assert!(now.year >= 2020, "{}", now);
+    assert!((1..=12).contains(&now.month) && (1..=31).contains(&now.day), "{}", now);
+    assert!(now.hour < 24 && now.minute < 60 && now.second < 60, "{}", now);
+    // The ticks and the RTC should not have drifted apart much since boot
+    assert!(wall_clock().as_secs().abs_diff(now.timestamp()) <= 5);
+}
@@ -12,6 +12,30 @@ lazy_static! {
     };
 }
 
+#[cfg(feature = "gdb_stub")]
+lazy_static! {
+    // COM2, where the GDB stub talks to the debugger
+    pub static ref SERIAL2: Mutex<SerialPort> = {
+        let mut serial_port = unsafe {
+            SerialPort::new(0x2f8)
+        };
+        serial_port.init();
+        Mutex::new(serial_port)
+    };
+}
+
+// A byte received on COM1, if one is waiting. Reads the UART registers directly since the
+// driver only has a blocking receive.
+pub fn try_receive() -> Option<u8> {
+    use x86_64::instructions::port::Port;
+    const DATA_READY: u8 = 1;
+    let mut line_status: Port<u8> = Port::new(0x3f8 + 5);
+    let mut data: Port<u8> = Port::new(0x3f8);
+    unsafe {
+        (line_status.read() & DATA_READY != 0).then(|| data.read())
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
@@ -13,7 +13,9 @@ mod hardware_interface;
 mod memory_management;
 mod emulation;
 mod logo;
-use hardware_interface::vga_buffer;
+mod debugging;
+mod time;
+use hardware_interface::{vga_buffer, serial, console, rtc};
 
 
 #[cfg(test)]
@@ -24,13 +26,11 @@ use core::panic::PanicInfo;
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
+    kprintln!("{}", info);
+    debugging::backtrace::print();
     loop {}
 }
 
-#[cfg(test)]
-use hardware_interface::serial;
-
 #[cfg(test)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
@@ -38,39 +38,78 @@ fn panic(info: &PanicInfo) -> ! {
 
     serial_println!("[Failed]\n");
     println!("Error: {}\n", info);
+    debugging::backtrace::print();
     exit_qemu(QemuExitCode::Failed);
 }
 
 fn init(boot_info: &'static BootInfo) {
-    use hardware_interface::{gdt, interrupts};
+    use hardware_interface::{acpi, gdt, interrupts};
     gdt::init();
     interrupts::init_idt();
     unsafe {
         interrupts::PICS.lock().initialize();
     }
     x86_64::instructions::interrupts::enable();
+    print!("Calibrating TSC...");
+    match time::tsc::init() {
+        Ok(hz) => println!("[ok]\n    {} MHz", hz / 1_000_000),
+        Err(err) => println!("[unavailable]\n    {}, timing falls back to the timer ticks", err),
+    }
     print!("Aquiring physical memory offset...");
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     println!("[ok]");
 
     print!("Creating Memory Mapper...");
-    let mut mapper = unsafe { memory_management::page_table::init(phys_mem_offset) };
+    unsafe { memory_management::page_table::init(phys_mem_offset) };
     println!("[ok]");
 
 
     print!("Creating Frame Allocator...");
-    let mut frame_allocator = unsafe { 
-        BootInfoFrameAllocator::init(&boot_info.memory_map) 
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    unsafe { 
+        frame_allocator.init(&boot_info.memory_map, phys_mem_offset)
     };
     println!("[ok]");
+    println!("    {} KiB used, {} KiB free",
+        frame_allocator.used_frames() * 4, frame_allocator.free_frames() * 4);
+
+    drop(frame_allocator);
+
+    print!("Reserving kernel address space...");
+    vmm::init(&boot_info.memory_map);
+    println!("[ok]");
 
     print!("Initializing Global Heap Allocator...");
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("[err: heap initialization failed]");
+    allocator::init_heap().expect("[err: heap initialization failed]");
     println!("[ok]");
+    let heap_stats = allocator::stats();
+    println!("    {} KiB heap, {} KiB free in {} region(s)",
+        heap_stats.heap_size / 1024, heap_stats.bytes_free / 1024, heap_stats.free_regions);
     
+    print!("Allocating kernel stacks...");
+    gdt::init_stacks().expect("[err: kernel stack allocation failed]");
+    println!("[ok]");
+
     print!("Testing heap allocation...");
     let heap_string = Box::new("[ok]");
     println!("{}", heap_string);
+
+    print!("Reading ACPI tables...");
+    match acpi::init(phys_mem_offset) {
+        Ok(tables) => {
+            println!("[ok]\n    ACPI revision {} from {}, {} tables",
+                tables.revision, acpi::Signature(&tables.oem_id), tables.tables.len());
+            tables.print_summary();
+        }
+        Err(err) => println!("[unavailable]\n    {}", err),
+    }
+
+    print!("Setting up interrupt controller...");
+    match interrupts::enable_apic() {
+        Ok(info) => println!("[ok]\n    local APIC {} with {} I/O APIC(s), timer at {} kHz",
+            info.local_apic_id, info.io_apics, info.timer_hz / 1000),
+        Err(err) => println!("[fallback]\n    8259 PICs, {}", err),
+    }
 }
 
 use bootloader::{BootInfo, entry_point};
@@ -81,7 +120,9 @@ use x86_64::VirtAddr;
 entry_point!(test_main);
 
 #[cfg(test)]
-fn test_main(_boot_info: &'static BootInfo) -> ! {
+fn test_main(boot_info: &'static BootInfo) -> ! {
+    init(boot_info);
+    rtc::init();
     test_run(); loop{}
 }
 
@@ -89,7 +130,7 @@ fn test_main(_boot_info: &'static BootInfo) -> ! {
 entry_point!(main);
 
 use alloc::boxed::Box;
-use memory_management::{page_table::BootInfoFrameAllocator, allocator};
+use memory_management::{frame_allocator::FRAME_ALLOCATOR, allocator, vmm};
 
 fn main(boot_info: &'static BootInfo) -> ! {
     vga_buffer::init();
@@ -97,6 +138,16 @@ fn main(boot_info: &'static BootInfo) -> ! {
     
     println!("Initializing...\n"); // 2 newlines are intentional
     init(boot_info);
+    // After init, so the RTC is read with the century register from the FADT
+    let boot_time = rtc::init();
+    println!("\ndeimOS booted ({})", boot_time);
+    #[cfg(feature = "gdb_stub")]
+    {
+        // Stops in the stub until GDB attaches and continues
+        print!("Waiting for GDB on COM2...");
+        x86_64::instructions::interrupts::int3();
+        println!("[ok]");
+    }
     logo::println_logo();
     hlt(); // call halt that way when interrupts aren't firing, the CPU isn't active
 }
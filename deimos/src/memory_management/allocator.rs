@@ -1,8 +1,50 @@
-use crate::{print, println};
-use crate::memory_management::linked_list::LinkedListAllocator;
+use crate::{print, println, serial_println};
+#[cfg(feature = "slab_allocator")]
+use deimos_alloc::fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(not(feature = "slab_allocator"))]
+use deimos_alloc::linked_list::LinkedListAllocator;
+use deimos_alloc::linked_list::Placement;
+use crate::memory_management::vmm;
+#[cfg(test)]
+use crate::memory_management::frame_allocator::FRAME_ALLOCATOR;
+#[cfg(feature = "heap_debug")]
+use crate::memory_management::heap_debug;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 4194304; // 2^22
+pub const HEAP_SIZE: usize = 4194304; // 2^22, mapped at boot
+pub const HEAP_MAX_SIZE: usize = 1 << 30; // default ceiling for on demand growth
+const HEAP_GROWTH_STEP: usize = 1 << 20; // the heap grows at least this much at once
+const PAGE_SIZE: usize = 4096;
+
+// How the linked list picks free regions. With the slab_allocator blocks in front of it,
+// the default build, first fit comes out faster and no more fragmented than best fit in
+// deimos_alloc/examples/placement_bench.rs.
+const HEAP_PLACEMENT: Placement = Placement::FirstFit;
+
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
+// Counters kept by the GlobalAlloc impl, in bytes as requested by the callers' layouts
+static BYTES_IN_USE: AtomicUsize = AtomicUsize::new(0);
+static PEAK_USAGE: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static FREES: AtomicUsize = AtomicUsize::new(0);
+static FAILED_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub heap_size: usize,
+    pub bytes_in_use: usize,
+    pub bytes_free: usize,
+    pub bytes_cached: usize, // free blocks held by the size class lists
+    pub free_regions: usize,
+    pub largest_free_block: usize,
+    pub peak_usage: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    pub failed_allocations: usize,
+}
 
 pub struct Locked<A> {
     inner: spin::Mutex<A>
@@ -18,78 +60,293 @@ impl<A> Locked<A> {
     pub fn lock(&self) -> spin::MutexGuard<'_, A> {
         self.inner.lock()
     }
+
+    // For exception handlers, which must not spin on a lock the interrupted code may hold
+    pub fn try_lock(&self) -> Option<spin::MutexGuard<'_, A>> {
+        self.inner.try_lock()
+    }
 }
 
+// Size class slabs in front of the linked list by default,
+// build without the "slab_allocator" feature to use the linked list alone
+#[cfg(feature = "slab_allocator")]
+pub type HeapAllocator = FixedSizeBlockAllocator;
+#[cfg(not(feature = "slab_allocator"))]
+pub type HeapAllocator = LinkedListAllocator;
+
 #[global_allocator]
-static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
+static ALLOCATOR: Locked<HeapAllocator> = Locked::new(HeapAllocator::with_placement(HEAP_PLACEMENT));
 
+use alloc::alloc::{GlobalAlloc, Layout};
 use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
-    },
+    structures::paging::{mapper::MapToError, PageTableFlags, Size4KiB},
     VirtAddr,
 };
 
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    print!("\n    Computing page range...");
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-    print!("[ok]\n    Allocating page range...");
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush()
-        };
+unsafe impl GlobalAlloc for Locked<HeapAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "heap_debug")]
+        let block_layout = heap_debug::padded_layout(layout);
+        #[cfg(not(feature = "heap_debug"))]
+        let block_layout = layout;
+
+        let mut allocator = self.lock();
+        let mut ptr = unsafe { allocator.allocate(block_layout) };
+        if ptr.is_null() && grow_heap(&mut allocator, block_layout) {
+            ptr = unsafe { allocator.allocate(block_layout) };
+        }
+
+        if ptr.is_null() {
+            FAILED_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        } else {
+            #[cfg(feature = "heap_debug")]
+            {
+                heap_debug::check_free_list(&allocator);
+                ptr = unsafe { heap_debug::arm(ptr, layout) };
+            }
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            let in_use = BYTES_IN_USE.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK_USAGE.fetch_max(in_use, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+
+        #[cfg(feature = "heap_debug")]
+        let (block, block_layout) = unsafe { heap_debug::disarm(&allocator, ptr, layout) };
+        #[cfg(not(feature = "heap_debug"))]
+        let (block, block_layout) = (ptr, layout);
+
+        unsafe { allocator.deallocate(block, block_layout) }
+        #[cfg(feature = "heap_debug")]
+        heap_debug::check_free_list(&allocator);
+
+        FREES.fetch_add(1, Ordering::Relaxed);
+        BYTES_IN_USE.fetch_sub(layout.size(), Ordering::Relaxed);
     }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // The redzones of heap_debug sit right behind the block, so it always moves then
+        #[cfg(not(feature = "heap_debug"))]
+        {
+            let mut allocator = self.lock();
+            let mut resized = unsafe { allocator.resize_in_place(ptr, layout, new_size) };
+            if !resized && new_size > layout.size() && allocator.reaches_heap_end(ptr, layout) {
+                let extra = Layout::from_size_align(new_size - layout.size(), layout.align()).unwrap();
+                resized = grow_heap(&mut allocator, extra)
+                    && unsafe { allocator.resize_in_place(ptr, layout, new_size) };
+            }
+            if resized {
+                if new_size > layout.size() {
+                    let grown_by = new_size - layout.size();
+                    let in_use = BYTES_IN_USE.fetch_add(grown_by, Ordering::Relaxed) + grown_by;
+                    PEAK_USAGE.fetch_max(in_use, Ordering::Relaxed);
+                } else {
+                    BYTES_IN_USE.fetch_sub(layout.size() - new_size, Ordering::Relaxed);
+                }
+                return ptr;
+            }
+        }
+
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+        }
+        new_ptr
+    }
+}
+
+// Maps the initial HEAP_SIZE bytes at HEAP_START.
+// Needs the global MAPPER and FRAME_ALLOCATOR to be initialized.
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    print!("\n    Allocating page range...");
+    map_heap_pages(HEAP_START, HEAP_SIZE)?;
     print!("[ok]\n    Initializing Allocator...");
     unsafe {
         ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
     }
     println!("[ok]");
 
-    print!("    Testing Allocator Coalescence...");
-    test_coalescence();
-    println!("[ok]");
-
     Ok(())
 }
 
+// Sets the maximum size the heap may grow to, rounded down to whole pages. It can not go
+// past HEAP_MAX_SIZE, which is all the address space vmm::init reserves for the heap.
+// Does not shrink a heap that is already bigger.
+#[allow(dead_code)]
+pub fn set_heap_limit(limit: usize) {
+    HEAP_LIMIT.store(limit.clamp(HEAP_SIZE, HEAP_MAX_SIZE) & !(PAGE_SIZE - 1), Ordering::Relaxed);
+}
 
-use alloc::vec::Vec;
-use core::alloc::Layout;
+#[allow(dead_code)]
+pub fn heap_size() -> usize {
+    ALLOCATOR.lock().heap_size()
+}
+
+// Takes a snapshot of the heap counters and walks the free list for the rest
+pub fn stats() -> HeapStats {
+    snapshot(&ALLOCATOR.lock())
+}
+
+// Like stats, for code that may have interrupted the allocator, None while it is busy
+pub fn try_stats() -> Option<HeapStats> {
+    let allocator = ALLOCATOR.try_lock()?;
+    Some(snapshot(&allocator))
+}
 
-fn test_coalescence() {
-    let chunk_size = 256;
-    let iterations = 14000;
-    let mut chunks = Vec::new();
+fn snapshot(allocator: &HeapAllocator) -> HeapStats {
+    let mut stats = HeapStats {
+        heap_size: allocator.heap_size(),
+        bytes_in_use: BYTES_IN_USE.load(Ordering::Relaxed),
+        bytes_free: 0,
+        bytes_cached: 0,
+        free_regions: 0,
+        largest_free_block: 0,
+        peak_usage: PEAK_USAGE.load(Ordering::Relaxed),
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        frees: FREES.load(Ordering::Relaxed),
+        failed_allocations: FAILED_ALLOCATIONS.load(Ordering::Relaxed),
+    };
+    for (_, size) in allocator.free_regions() {
+        stats.bytes_free += size;
+        stats.free_regions += 1;
+        stats.largest_free_block = stats.largest_free_block.max(size);
+    }
+    #[cfg(feature = "slab_allocator")]
+    {
+        stats.bytes_cached = allocator.cached_bytes();
+    }
+    stats
+}
 
-    for _ in 0..iterations {
-        let mut v = Vec::<u8>::with_capacity(chunk_size);
-        v.push(1);
-        chunks.push(v);
+// Starts a new peak usage measurement from the current usage
+#[allow(dead_code)]
+pub fn reset_peak_usage() {
+    PEAK_USAGE.store(BYTES_IN_USE.load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+// Prints every free region to serial, useful for looking at fragmentation
+#[allow(dead_code)]
+pub fn dump_free_list() {
+    let allocator = ALLOCATOR.lock();
+    serial_println!("Heap free list ({:#x}..{:#x}):", HEAP_START, allocator.heap_end());
+    for (start, size) in allocator.free_regions() {
+        serial_println!("    {:#x}..{:#x} {:>10} bytes", start, start + size, size);
     }
+}
+
+// Gives free pages at the end of the heap back to the frame allocator,
+// never going below the initial HEAP_SIZE. Returns the number of bytes released.
+#[allow(dead_code)]
+pub fn shrink_heap() -> usize {
+    let mut allocator = ALLOCATOR.lock();
+    let Some((start, size)) = allocator.shrink(PAGE_SIZE, HEAP_SIZE) else {
+        return 0;
+    };
+    vmm::unmap_range(VirtAddr::new(start as u64), size as u64);
+    size
+}
 
-    drop(chunks);
+// Called with the allocator locked when an allocation of `layout` did not fit.
+// Maps enough new pages after the end of the heap to satisfy it, up to the heap limit.
+fn grow_heap(allocator: &mut HeapAllocator, layout: Layout) -> bool {
+    let heap_end = allocator.heap_end();
+    let limit_end = HEAP_START + HEAP_LIMIT.load(Ordering::Relaxed);
+    let room = limit_end.saturating_sub(heap_end);
 
-    let giant_layout = Layout::from_size_align(chunk_size * iterations, 8).unwrap();
-    unsafe {
-        let ptr = alloc::alloc::alloc(giant_layout);
-        
-        if ptr.is_null() {
-            panic!("Fragmentation Test Failed: Could not coalesce blocks!");
-        } else {
-            alloc::alloc::dealloc(ptr, giant_layout);
+    // Worst case the new memory does not coalesce with a free region before it
+    let needed = layout.size().max(1).saturating_add(layout.align());
+    let grow_by = needed.max(HEAP_GROWTH_STEP).next_multiple_of(PAGE_SIZE).min(room);
+    if grow_by < needed {
+        return false;
+    }
+
+    match map_heap_pages(heap_end, grow_by) {
+        Ok(()) => {
+            unsafe { allocator.extend(grow_by) };
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+// The heap's virtual range is reserved by vmm::init, only the frames are mapped here
+fn map_heap_pages(start: usize, size: usize) -> Result<(), MapToError<Size4KiB>> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    vmm::map_range(VirtAddr::new(start as u64), size as u64, flags)
+}
+
+#[cfg(test)]
+use alloc::vec::Vec;
+
+#[test_case]
+fn test_heap_growth() {
+    let big = Vec::<u8>::with_capacity(HEAP_SIZE * 2);
+    assert!(heap_size() > HEAP_SIZE * 2);
+    drop(big);
+
+    // Page tables created while growing stay mapped, only the heap frames come back
+    let frames_before = FRAME_ALLOCATOR.lock().free_frames();
+    let released = shrink_heap();
+    assert!(released > 0);
+    assert_eq!(heap_size(), HEAP_SIZE);
+    assert_eq!(FRAME_ALLOCATOR.lock().free_frames(), frames_before + released / PAGE_SIZE);
+}
+
+#[test_case]
+fn test_heap_limit_is_capped() {
+    set_heap_limit(usize::MAX);
+    assert_eq!(HEAP_LIMIT.load(Ordering::Relaxed), HEAP_MAX_SIZE);
+    // Growing past the reserved region fails instead of mapping over whatever follows it
+    assert!(Vec::<u8>::new().try_reserve(HEAP_MAX_SIZE).is_err());
+    assert!(heap_size() <= HEAP_MAX_SIZE);
+
+    set_heap_limit(0);
+    assert_eq!(HEAP_LIMIT.load(Ordering::Relaxed), HEAP_SIZE);
+    set_heap_limit(HEAP_MAX_SIZE);
+}
+
+#[test_case]
+fn test_heap_stats() {
+    let before = stats();
+    let data = Vec::<u8>::with_capacity(64 * 1024);
+    let during = stats();
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert_eq!(during.bytes_in_use, before.bytes_in_use + 64 * 1024);
+    assert!(during.peak_usage >= during.bytes_in_use);
+    assert!(during.largest_free_block <= during.bytes_free);
+
+    drop(data);
+    let after = stats();
+    assert_eq!(after.frees, before.frees + 1);
+    assert_eq!(after.bytes_in_use, before.bytes_in_use);
+}
+
+#[cfg(feature = "slab_allocator")]
+#[test_case]
+fn test_size_class_reuse() {
+    use alloc::boxed::Box;
+
+    // A freed block goes back to the head of its class list and is handed out next
+    let first = Box::new(0xdead_u64);
+    let first_addr = &*first as *const u64 as usize;
+    drop(first);
+    let second = Box::new(0xbeef_u64);
+    assert_eq!(&*second as *const u64 as usize, first_addr);
+
+    for &size in deimos_alloc::fixed_size_block::BLOCK_SIZES {
+        let layout = Layout::from_size_align(size, size).unwrap();
+        unsafe {
+            let ptr = alloc::alloc::alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % size, 0);
+            alloc::alloc::dealloc(ptr, layout);
         }
     }
 }
@@ -0,0 +1,231 @@
+use x86_64::{
+    PhysAddr, VirtAddr, structures::paging::{
+        FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB, Size4KiB
+    }
+};
+use bootloader::bootinfo::{
+    MemoryMap,
+    MemoryRegionType,
+};
+use super::allocator::Locked;
+
+const FRAME_SIZE: u64 = 4096;
+const FRAMES_PER_HUGE_PAGE: usize = 512; // 2 MiB / 4 KiB
+const BITS_PER_WORD: usize = 64;
+
+// The one physical frame allocator for the whole kernel, initialized from the boot info
+pub static FRAME_ALLOCATOR: Locked<BitmapFrameAllocator> = Locked::new(BitmapFrameAllocator::new());
+
+// A FrameAllocator that tracks every physical frame with one bit (1 = in use, 0 = free).
+// The bitmap itself lives in the first usable region big enough to hold it and is accessed
+// through the bootloader's physical memory mapping.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    usable_frames: usize,
+    free_frames: usize,
+    next_free_word: usize, // search hint, every word before it is known to be full
+}
+
+impl BitmapFrameAllocator {
+    pub const fn new() -> Self {
+        BitmapFrameAllocator {
+            bitmap: &mut [],
+            usable_frames: 0,
+            free_frames: 0,
+            next_free_word: 0,
+        }
+    }
+
+    //This function is unsafe: caller must guarentee that the memory map is valid, that
+    //the complete physical memory is mapped at phys_mem_offset and that no usable frame
+    //is already in use
+    pub unsafe fn init(&mut self, memory_map: &'static MemoryMap, phys_mem_offset: VirtAddr) {
+        let usable = || memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        let frame_count = usable()
+            .map(|r| (r.range.end_addr() / FRAME_SIZE) as usize)
+            .max()
+            .unwrap_or(0);
+        let word_count = frame_count.div_ceil(BITS_PER_WORD);
+        let bitmap_bytes = (word_count * core::mem::size_of::<u64>()) as u64;
+
+        let bitmap_start = usable()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_bytes)
+            .map(|r| r.range.start_addr())
+            .expect("[err: no usable region can hold the frame bitmap]");
+
+        let bitmap_ptr = (phys_mem_offset + bitmap_start).as_mut_ptr::<u64>();
+        self.bitmap = unsafe { core::slice::from_raw_parts_mut(bitmap_ptr, word_count) };
+        self.bitmap.fill(u64::MAX); // everything starts out reserved
+
+        for region in usable() {
+            let start = (region.range.start_addr() / FRAME_SIZE) as usize;
+            let end = (region.range.end_addr() / FRAME_SIZE) as usize;
+            for frame in start..end {
+                self.clear_bit(frame);
+            }
+            self.usable_frames += end - start;
+        }
+
+        // The bitmap must not hand out the frames it is stored in
+        let bitmap_first = (bitmap_start / FRAME_SIZE) as usize;
+        let bitmap_frames = bitmap_bytes.div_ceil(FRAME_SIZE) as usize;
+        for frame in bitmap_first..bitmap_first + bitmap_frames {
+            self.set_bit(frame);
+        }
+        self.usable_frames -= bitmap_frames;
+        self.free_frames = self.usable_frames;
+        self.next_free_word = 0;
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    pub fn used_frames(&self) -> usize {
+        self.usable_frames - self.free_frames
+    }
+
+    #[allow(dead_code)]
+    pub fn total_frames(&self) -> usize {
+        self.usable_frames
+    }
+
+    // Allocates `count` physically contiguous frames whose first frame number is a multiple
+    // of `align` frames. Returns the first frame of the run.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame<Size4KiB>> {
+        assert!(count > 0 && align.is_power_of_two());
+        if count > self.free_frames {
+            return None;
+        }
+
+        let frame_count = self.bitmap.len() * BITS_PER_WORD;
+        let mut start = self.next_free_word * BITS_PER_WORD;
+        start = start.next_multiple_of(align);
+
+        while start + count <= frame_count {
+            match (start..start + count).rev().find(|&frame| self.is_used(frame)) {
+                // Restart the search past the used frame that broke the run
+                Some(used) => start = (used + 1).next_multiple_of(align),
+                None => {
+                    for frame in start..start + count {
+                        self.set_bit(frame);
+                    }
+                    self.free_frames -= count;
+                    return Some(Self::frame_at(start));
+                }
+            }
+        }
+        None
+    }
+
+    //This function is unsafe: caller must guarentee that the frames were returned by
+    //allocate_contiguous (or allocate_frame) and are no longer referenced anywhere
+    pub unsafe fn deallocate_contiguous(&mut self, first: PhysFrame<Size4KiB>, count: usize) {
+        let first = (first.start_address().as_u64() / FRAME_SIZE) as usize;
+        for frame in first..first + count {
+            assert!(frame < self.bitmap.len() * BITS_PER_WORD && self.is_used(frame),
+                "[err: frame {:#x} freed but not allocated]", frame as u64 * FRAME_SIZE);
+            self.clear_bit(frame);
+        }
+        self.free_frames += count;
+    }
+
+    fn frame_at(frame: usize) -> PhysFrame<Size4KiB> {
+        PhysFrame::containing_address(PhysAddr::new(frame as u64 * FRAME_SIZE))
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / BITS_PER_WORD] & (1 << (frame % BITS_PER_WORD)) != 0
+    }
+
+    fn set_bit(&mut self, frame: usize) {
+        self.bitmap[frame / BITS_PER_WORD] |= 1 << (frame % BITS_PER_WORD);
+    }
+
+    fn clear_bit(&mut self, frame: usize) {
+        let word = frame / BITS_PER_WORD;
+        self.bitmap[word] &= !(1 << (frame % BITS_PER_WORD));
+        if word < self.next_free_word {
+            self.next_free_word = word;
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        // Skip over full words, which makes single frame allocation O(1) amortized
+        let word = (self.next_free_word..self.bitmap.len())
+            .find(|&w| self.bitmap[w] != u64::MAX)?;
+        self.next_free_word = word;
+
+        let frame = word * BITS_PER_WORD + self.bitmap[word].trailing_ones() as usize;
+        self.set_bit(frame);
+        self.free_frames -= 1;
+        Some(Self::frame_at(frame))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        unsafe { self.deallocate_contiguous(frame, 1) }
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let first = self.allocate_contiguous(FRAMES_PER_HUGE_PAGE, FRAMES_PER_HUGE_PAGE)?;
+        PhysFrame::from_start_address(first.start_address()).ok()
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let first = PhysFrame::containing_address(frame.start_address());
+        unsafe { self.deallocate_contiguous(first, FRAMES_PER_HUGE_PAGE) }
+    }
+}
+
+#[test_case]
+fn test_frame_alloc_dealloc() {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let free_before = allocator.free_frames();
+
+    let frame: PhysFrame<Size4KiB> = allocator.allocate_frame().expect("out of frames");
+    assert_eq!(allocator.free_frames(), free_before - 1);
+
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.free_frames(), free_before);
+
+    // A freed frame is the first candidate for the next allocation
+    let again: PhysFrame<Size4KiB> = allocator.allocate_frame().expect("out of frames");
+    assert_eq!(again, frame);
+    unsafe { allocator.deallocate_frame(again) };
+}
+
+#[test_case]
+fn test_frame_alloc_huge() {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let free_before = allocator.free_frames();
+
+    let frame: PhysFrame<Size2MiB> = allocator.allocate_frame().expect("no free 2 MiB frame");
+    assert_eq!(frame.start_address().as_u64() % (2 * 1024 * 1024), 0);
+    assert_eq!(allocator.free_frames(), free_before - FRAMES_PER_HUGE_PAGE);
+
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.free_frames(), free_before);
+}
+
+#[test_case]
+fn test_frame_alloc_contiguous() {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let first = allocator.allocate_contiguous(16, 4).expect("no 16 contiguous frames");
+    assert_eq!((first.start_address().as_u64() / FRAME_SIZE) % 4, 0);
+    let next: PhysFrame<Size4KiB> = allocator.allocate_frame().unwrap();
+    assert!(next < first || next >= first + 16);
+    unsafe {
+        allocator.deallocate_frame(next);
+        allocator.deallocate_contiguous(first, 16);
+    }
+}
@@ -0,0 +1,144 @@
+// Heap corruption checks, compiled in with the "heap_debug" feature.
+//
+// Every allocation is padded with redzones and carries a header just before the pointer
+// handed out:
+//
+//   | front redzone ... | header (magic, size) | user data | back redzone |
+//   ^ block start                              ^ returned pointer
+//
+// The start of the front redzone is left for the allocator's own free list node, so the
+// header survives the block being freed and a second free of it can be recognised.
+
+use core::{mem, ptr};
+use alloc::alloc::Layout;
+use super::allocator::{HeapAllocator, HEAP_START};
+
+const ALLOCATED_MAGIC: u64 = 0xa110_ca7e_d0d0_cafe;
+const FREED_MAGIC: u64 = 0xf4ee_d0d0_dead_beef;
+
+const REDZONE_BYTE: u8 = 0xfd;
+const NEW_BYTE: u8 = 0xcd; // fresh allocations, catches use of uninitialized memory
+const FREED_BYTE: u8 = 0xdd; // freed allocations, catches use after free
+
+const FRONT_REDZONE: usize = 32; // at least room for a free list node plus the header
+const BACK_REDZONE: usize = 16;
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    size: usize,
+}
+
+fn front_size(layout: Layout) -> usize {
+    FRONT_REDZONE.next_multiple_of(layout.align())
+}
+
+// The layout actually requested from the allocator for a user layout
+pub fn padded_layout(layout: Layout) -> Layout {
+    let size = front_size(layout) + layout.size() + BACK_REDZONE;
+    Layout::from_size_align(size, layout.align().max(mem::align_of::<Header>()))
+        .expect("[err: heap_debug padded layout overflow]")
+}
+
+// Writes redzones and header into a fresh block and returns the pointer for the user
+pub unsafe fn arm(block: *mut u8, layout: Layout) -> *mut u8 {
+    let front = front_size(layout);
+    unsafe {
+        let user = block.add(front);
+        ptr::write_bytes(block, REDZONE_BYTE, front - mem::size_of::<Header>());
+        header(user).write(Header { magic: ALLOCATED_MAGIC, size: layout.size() });
+        ptr::write_bytes(user, NEW_BYTE, layout.size());
+        ptr::write_bytes(user.add(layout.size()), REDZONE_BYTE, BACK_REDZONE);
+        user
+    }
+}
+
+// Checks a pointer that is about to be freed, poisons it and returns the original
+// block and its padded layout. Panics on anything that would corrupt the heap.
+pub unsafe fn disarm(allocator: &HeapAllocator, user: *mut u8, layout: Layout) -> (*mut u8, Layout) {
+    let addr = user as usize;
+    let front = front_size(layout);
+    if addr < HEAP_START + front || addr >= allocator.heap_end() || !addr.is_multiple_of(layout.align()) {
+        report(allocator, "free of a pointer that did not come from the heap", addr, layout);
+    }
+
+    let block = unsafe { user.sub(front) };
+    let padded = padded_layout(layout);
+    let recorded = unsafe { header(user).read() };
+    match recorded.magic {
+        ALLOCATED_MAGIC => {}
+        FREED_MAGIC => report(allocator, "double free", addr, layout),
+        _ => report(allocator, "free of a pointer with a corrupt or missing header", addr, layout),
+    }
+    if recorded.size != layout.size() {
+        report(allocator, "free with a layout that does not match the allocation", addr, layout);
+    }
+
+    let block_start = block as usize;
+    let block_end = block_start + padded.size();
+    if allocator.free_regions().any(|(start, size)| start < block_end && block_start < start + size) {
+        report(allocator, "freed block overlaps a free region", addr, layout);
+    }
+
+    let front_ok = (0..front - mem::size_of::<Header>())
+        .all(|i| unsafe { *block.add(i) } == REDZONE_BYTE);
+    if !front_ok {
+        report(allocator, "front redzone overwritten (buffer underflow)", addr, layout);
+    }
+    let back_ok = (0..BACK_REDZONE)
+        .all(|i| unsafe { *user.add(layout.size() + i) } == REDZONE_BYTE);
+    if !back_ok {
+        report(allocator, "back redzone overwritten (buffer overflow)", addr, layout);
+    }
+
+    unsafe {
+        header(user).write(Header { magic: FREED_MAGIC, size: layout.size() });
+        ptr::write_bytes(user, FREED_BYTE, layout.size());
+    }
+    (block, padded)
+}
+
+// Checks that the free list is sorted, non overlapping and inside the heap
+pub fn check_free_list(allocator: &HeapAllocator) {
+    let heap_end = allocator.heap_end();
+    let mut previous_end = HEAP_START;
+    for (start, size) in allocator.free_regions() {
+        if start < previous_end {
+            report(allocator, "free list is unsorted or has overlapping regions", start, Layout::new::<u8>());
+        }
+        if start + size > heap_end {
+            report(allocator, "free region reaches past the end of the heap", start, Layout::new::<u8>());
+        }
+        previous_end = start + size;
+    }
+}
+
+fn header(user: *mut u8) -> *mut Header {
+    user.wrapping_sub(mem::size_of::<Header>()) as *mut Header
+}
+
+fn report(allocator: &HeapAllocator, problem: &str, addr: usize, layout: Layout) -> ! {
+    use crate::serial_println;
+
+    // The allocator is locked, so this dumps the free list without going through dump_free_list
+    serial_println!("heap_debug: free list at time of error:");
+    for (start, size) in allocator.free_regions().take(64) {
+        serial_println!("    {:#x}..{:#x} {:>10} bytes", start, start + size, size);
+    }
+    panic!("heap_debug: {} at {:#x} (size {}, align {})",
+        problem, addr, layout.size(), layout.align());
+}
+
+#[test_case]
+fn test_heap_debug_poisoning() {
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    unsafe {
+        let ptr = alloc::alloc::alloc(layout);
+        assert!((0..layout.size()).all(|i| *ptr.add(i) == NEW_BYTE));
+        assert_eq!((*header(ptr)).magic, ALLOCATED_MAGIC);
+
+        alloc::alloc::dealloc(ptr, layout);
+        assert!((0..layout.size()).all(|i| *ptr.add(i) == FREED_BYTE));
+        assert_eq!((*header(ptr)).magic, FREED_MAGIC);
+    }
+}
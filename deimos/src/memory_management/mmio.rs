@@ -0,0 +1,116 @@
+// Mappings of device register windows (PCI BARs, the APIC, ...) into kernel memory.
+//
+// The window is mapped uncached through the VMM and only accessed with volatile reads and
+// writes of plain integer registers. Dropping the MmioRegion unmaps it again, the physical
+// range belongs to the device and is never handed to the frame allocator.
+
+use core::{marker::PhantomData, mem};
+use x86_64::{PhysAddr, VirtAddr, structures::paging::PageTableFlags};
+use super::vmm::{VMM, VmmError};
+
+const PAGE_SIZE: u64 = 4096;
+
+pub struct MmioRegion {
+    base: VirtAddr, // start of the page aligned mapping, which is what the VMM knows it by
+    virt: VirtAddr, // virtual address of phys
+    phys: PhysAddr,
+    len: usize,
+}
+
+// Maps the device memory phys..phys + len, which does not have to be page aligned
+pub fn map_mmio(phys: PhysAddr, len: usize) -> Result<MmioRegion, VmmError> {
+    assert!(len > 0);
+    let phys_base = phys.align_down(PAGE_SIZE);
+    let offset = phys - phys_base;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+
+    let base = VMM.lock().map_physical("mmio", phys_base, offset + len as u64, flags)?;
+    Ok(MmioRegion { base, virt: base + offset, phys, len })
+}
+
+// Register widths the hardware can be accessed with
+pub trait MmioValue: Copy {}
+impl MmioValue for u8 {}
+impl MmioValue for u16 {}
+impl MmioValue for u32 {}
+impl MmioValue for u64 {}
+
+#[allow(dead_code)]
+impl MmioRegion {
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn read<T: MmioValue>(&self, offset: usize) -> T {
+        unsafe { self.register_ptr::<T>(offset).read_volatile() }
+    }
+
+    pub fn write<T: MmioValue>(&self, offset: usize, value: T) {
+        unsafe { self.register_ptr::<T>(offset).write_volatile(value) }
+    }
+
+    // A typed handle for one register, for drivers that keep their registers in a struct
+    pub fn register<T: MmioValue>(&self, offset: usize) -> Register<'_, T> {
+        Register { ptr: self.register_ptr(offset), _region: PhantomData }
+    }
+
+    // Panics on accesses outside the window or not naturally aligned,
+    // devices tend to ignore or misbehave on those instead of faulting
+    fn register_ptr<T: MmioValue>(&self, offset: usize) -> *mut T {
+        let size = mem::size_of::<T>();
+        assert!(offset.checked_add(size).is_some_and(|end| end <= self.len),
+            "[err: mmio access at {:#x} outside of {:#x} bytes]", offset, self.len);
+        let addr = self.virt + offset as u64;
+        assert!(addr.is_aligned(size as u64), "[err: unaligned mmio access at {:#x}]", offset);
+        addr.as_mut_ptr()
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        VMM.lock().unmap(self.base).expect("[err: mmio region was not mapped]");
+    }
+}
+
+pub struct Register<'a, T: MmioValue> {
+    ptr: *mut T,
+    _region: PhantomData<&'a MmioRegion>,
+}
+
+#[allow(dead_code)]
+impl<T: MmioValue> Register<'_, T> {
+    pub fn read(&self) -> T {
+        unsafe { self.ptr.read_volatile() }
+    }
+
+    pub fn write(&self, value: T) {
+        unsafe { self.ptr.write_volatile(value) }
+    }
+}
+
+#[test_case]
+fn test_mmio_vga_buffer() {
+    // The bootloader identity maps the VGA text buffer, so both mappings can be compared
+    let identity = 0xb8000 as *mut u16;
+    let region = map_mmio(PhysAddr::new(0xb8000 + 2), 2 * 80 * 25 - 2).expect("mmio mapping failed");
+    assert_eq!(region.virt_addr().as_u64() % PAGE_SIZE, 2);
+
+    let old = unsafe { identity.add(1).read_volatile() };
+    region.write::<u16>(0, 0x0f00 | b'M' as u16);
+    assert_eq!(unsafe { identity.add(1).read_volatile() }, 0x0f00 | b'M' as u16);
+    unsafe { identity.add(1).write_volatile(old) };
+    assert_eq!(region.register::<u16>(0).read(), old);
+
+    let base = region.virt_addr().align_down(PAGE_SIZE);
+    drop(region);
+    assert!(VMM.lock().find(base).is_none());
+}
@@ -1,4 +1,9 @@
 pub mod allocator;
 pub mod page_table;
-pub mod linked_list;
+pub mod frame_allocator;
+pub mod vmm;
+pub mod mmio;
+pub mod stack;
 
+#[cfg(feature = "heap_debug")]
+pub mod heap_debug;
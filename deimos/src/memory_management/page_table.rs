@@ -1,53 +1,222 @@
+use core::fmt;
 use x86_64::{
-    PhysAddr, VirtAddr, structures::paging::{
-        FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB
+    PhysAddr, VirtAddr, registers::control::Cr3, structures::paging::{
+        OffsetPageTable, PageTable, PageTableFlags, Translate
     }
 };
-use bootloader::bootinfo::{
-    MemoryMap,
-    MemoryRegionType,
-};
+use crate::serial_println;
+use super::allocator::Locked;
+
+// The kernel's page table mapper, set up once by init.
+// Never allocate on the heap while holding this lock: heap growth needs it too.
+pub static MAPPER: Locked<Option<OffsetPageTable<'static>>> = Locked::new(None);
 
-// A FrameAllocator that returns usable frames from the Bootloader's Memory Map
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize
+//This function is unsafe for the same reasons as active_level_4_table
+pub unsafe fn init(phys_mem_offset: VirtAddr) {
+    unsafe {
+        let l4_table = active_level_4_table(phys_mem_offset);
+        *MAPPER.lock() = Some(OffsetPageTable::new(l4_table, phys_mem_offset));
+    }
 }
 
-impl BootInfoFrameAllocator {
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0
+// Whether every byte of addr..addr + len is mapped. Does not wait for the mapper, so it is
+// usable from exception handlers, and says no while the page tables are being changed.
+pub fn is_mapped(addr: u64, len: u64) -> bool {
+    let Some(mapper) = MAPPER.try_lock() else { return false };
+    let Some(mapper) = mapper.as_ref() else { return false };
+    let Some(end) = addr.checked_add(len) else { return false };
+    let mut page = addr & !0xfff;
+    while page < end {
+        if !VirtAddr::try_new(page).is_ok_and(|page| mapper.translate_addr(page).is_some()) {
+            return false;
         }
+        page += 0x1000;
     }
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_map.iter();
-        let usable_regions = regions
-            .filter(|r| r.region_type == MemoryRegionType::Usable);
-        let addr_ranges = usable_regions
-            .map(|r| r.range.start_addr()..r.range.end_addr());
-        let frame_addrs = addr_ranges
-            .flat_map(|r| r.step_by(4096));
-        frame_addrs.map(|addr| PhysFrame::containing_address(
-                PhysAddr::new(addr)))
+    true
+}
+
+// One level of a page table walk: which entry of which table it went through
+#[derive(Debug, Clone, Copy)]
+pub struct WalkStep {
+    pub level: u8,
+    pub index: u16,
+    pub table: PhysAddr,
+    pub entry: u64,
+}
+
+impl WalkStep {
+    pub fn flags(&self) -> PageTableFlags {
+        PageTableFlags::from_bits_truncate(self.entry)
     }
+}
 
+// The entries the CPU reads to translate addr, from the level 4 table down to the page or
+// the first entry that is not present
+pub struct Translation {
+    pub addr: VirtAddr,
+    pub steps: [Option<WalkStep>; 4],
+    pub phys: Option<PhysAddr>,
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+impl fmt::Display for Translation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for step in self.steps.iter().flatten() {
+            writeln!(f, "L{} table {:#x} [{:>3}] = {:#018x} {:?}",
+                step.level, step.table.as_u64(), step.index, step.entry, step.flags())?;
+        }
+        match self.phys {
+            Some(phys) => writeln!(f, "{:#x} -> {:#x}", self.addr.as_u64(), phys.as_u64()),
+            None => writeln!(f, "{:#x} is not mapped", self.addr.as_u64()),
+        }
     }
 }
 
-pub unsafe fn init(phys_mem_offset: VirtAddr) -> OffsetPageTable<'static> {
-    unsafe {
-        let l4_table = active_level_4_table(phys_mem_offset);
-        OffsetPageTable::new(l4_table, phys_mem_offset)
+// Walks the page tables for addr, recording every level. None when the mapper is busy, so
+// this can be used from exception handlers and the monitor.
+pub fn translate_verbose(addr: VirtAddr) -> Option<Translation> {
+    let mapper = MAPPER.try_lock()?;
+    let mapper = mapper.as_ref()?;
+    let phys_offset = mapper.phys_offset();
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+
+    let mut translation = Translation { addr, steps: [None; 4], phys: None };
+    let mut table_addr = Cr3::read().0.start_address();
+    for (depth, index) in indices.into_iter().enumerate() {
+        let level = 4 - depth as u8;
+        let table = unsafe { table_at(phys_offset, table_addr) };
+        let entry = &table[index];
+        translation.steps[depth] = Some(WalkStep {
+            level,
+            index: u16::from(index),
+            table: table_addr,
+            entry: entry.flags().bits() | entry.addr().as_u64(),
+        });
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return Some(translation);
+        }
+        // A huge page or the 4 KiB page of level 1 ends the walk
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            let page_size = 1u64 << (12 + 9 * (level - 1));
+            translation.phys = Some(entry.addr() + (addr.as_u64() & (page_size - 1)));
+            return Some(translation);
+        }
+        table_addr = entry.addr();
     }
+    Some(translation)
+}
+
+// A run of pages that are contiguous in virtual and physical memory and have the same access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Mapping {
+    virt: u64,
+    phys: u64,
+    size: u64,
+    access: Access,
+}
+
+// What the entries of all levels allow together: writing and user access need every level
+// to allow it, a single no execute bit forbids execution
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Access {
+    writable: bool,
+    user: bool,
+    no_execute: bool,
+    huge: bool,
+}
+
+impl Access {
+    fn through(self, flags: PageTableFlags) -> Access {
+        Access {
+            writable: self.writable && flags.contains(PageTableFlags::WRITABLE),
+            user: self.user && flags.contains(PageTableFlags::USER_ACCESSIBLE),
+            no_execute: self.no_execute || flags.contains(PageTableFlags::NO_EXECUTE),
+            huge: flags.contains(PageTableFlags::HUGE_PAGE),
+        }
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "r{}{} {}",
+            if self.writable { 'w' } else { '-' },
+            if self.no_execute { '-' } else { 'x' },
+            if self.user { "user" } else { "kernel" })?;
+        if self.huge {
+            write!(f, " huge")?;
+        }
+        Ok(())
+    }
+}
+
+impl Mapping {
+    // Appends next if it continues this mapping, otherwise returns it back
+    fn extend(&mut self, next: Mapping) -> Result<(), Mapping> {
+        if next.virt == self.virt + self.size && next.phys == self.phys + self.size && next.access == self.access {
+            self.size += next.size;
+            Ok(())
+        } else {
+            Err(next)
+        }
+    }
+}
+
+// Prints every mapping of the active page tables to serial, merging pages that continue
+// each other into one line
+#[allow(dead_code)]
+pub fn dump_mappings() {
+    let mapper = MAPPER.lock();
+    let mapper = mapper.as_ref().expect("[err: mapper not initialized]");
+    let phys_offset = mapper.phys_offset();
+    // The lock is only held so the tables do not change during the walk
+    let level_4_table = unsafe { table_at(phys_offset, Cr3::read().0.start_address()) };
+    let all = Access { writable: true, user: true, no_execute: false, huge: false };
+
+    serial_println!("Page table mappings:");
+    let mut current: Option<Mapping> = None;
+    walk_table(phys_offset, level_4_table, 4, 0, all, &mut |mapping| {
+        match current.as_mut().map(|current| current.extend(mapping)) {
+            Some(Ok(())) => {}
+            Some(Err(next)) => {
+                print_mapping(current.replace(next).unwrap());
+            }
+            None => current = Some(mapping),
+        }
+    });
+    if let Some(mapping) = current {
+        print_mapping(mapping);
+    }
+}
+
+fn print_mapping(mapping: Mapping) {
+    serial_println!("    {:#018x}..{:#018x} -> {:#014x} {:>10} KiB {}",
+        mapping.virt, mapping.virt + mapping.size, mapping.phys, mapping.size / 1024, mapping.access);
+}
+
+// Calls f for every page mapped below table, in address order
+fn walk_table(phys_offset: VirtAddr, table: &PageTable, level: u8, base: u64, access: Access,
+    f: &mut impl FnMut(Mapping)) {
+    let entry_size = 1u64 << (12 + 9 * (level - 1));
+    for (index, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        // Sign extends the upper half
+        let virt = VirtAddr::new_truncate(base + index as u64 * entry_size).as_u64();
+        let access = access.through(flags);
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            f(Mapping { virt, phys: entry.addr().as_u64(), size: entry_size, access });
+        } else {
+            let next = unsafe { table_at(phys_offset, entry.addr()) };
+            walk_table(phys_offset, next, level - 1, virt, access, f);
+        }
+    }
+}
+
+// The page table in the frame at phys. Unsafe because phys must hold a page table and all
+// of physical memory must be mapped at phys_offset.
+unsafe fn table_at(phys_offset: VirtAddr, phys: PhysAddr) -> &'static PageTable {
+    unsafe { &*(phys_offset + phys.as_u64()).as_ptr() }
 }
 
 //This function is unsafe: caller must guarentee that the complete 
@@ -56,8 +225,6 @@ pub unsafe fn init(phys_mem_offset: VirtAddr) -> OffsetPageTable<'static> {
 unsafe fn active_level_4_table(phys_mem_offset: VirtAddr)
     -> &'static mut PageTable
 {
-    use x86_64::registers::control::Cr3;
-
     let phys = Cr3::read().0.start_address();
     let virt = phys_mem_offset + phys.as_u64();
     let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
@@ -65,3 +232,28 @@ unsafe fn active_level_4_table(phys_mem_offset: VirtAddr)
 
 }
 
+#[test_case]
+fn test_translate_verbose() {
+    let local = 0u64;
+    let addr = VirtAddr::from_ptr(&local);
+    let translation = translate_verbose(addr).expect("mapper locked");
+    let expected = MAPPER.lock().as_ref().unwrap().translate_addr(addr);
+    assert_eq!(translation.phys, expected);
+    let first = translation.steps[0].unwrap();
+    assert_eq!((first.level, first.index), (4, u16::from(addr.p4_index())));
+    let last = translation.steps.iter().flatten().last().unwrap();
+    assert!(last.level == 1 || last.flags().contains(PageTableFlags::HUGE_PAGE));
+}
+
+#[test_case]
+fn test_mapping_coalescing() {
+    let access = Access { writable: true, user: false, no_execute: true, huge: false };
+    let mut mapping = Mapping { virt: 0x1000, phys: 0x5000, size: 0x1000, access };
+    assert_eq!(mapping.extend(Mapping { virt: 0x2000, phys: 0x6000, size: 0x1000, access }), Ok(()));
+    assert_eq!(mapping.size, 0x2000);
+    // Not physically contiguous, and different access
+    let moved = Mapping { virt: 0x3000, phys: 0x9000, size: 0x1000, access };
+    assert_eq!(mapping.extend(moved), Err(moved));
+    let read_only = Mapping { virt: 0x3000, phys: 0x7000, size: 0x1000, access: Access { writable: false, ..access } };
+    assert_eq!(mapping.extend(read_only), Err(read_only));
+}
@@ -0,0 +1,98 @@
+// Kernel stacks with an unmapped guard page below each one.
+//
+// A stack overflow runs into the guard page and faults, the fault handlers ask guard_hit
+// which stack it was. Stacks are allocated from the VMM, the boot stack is registered
+// there by vmm::init.
+
+use x86_64::{VirtAddr, structures::paging::PageTableFlags};
+use super::vmm::{Backing, VMM, VmmError};
+
+const PAGE_SIZE: u64 = 4096;
+
+pub const KERNEL_STACK_SIZE: u64 = 64 * 1024;
+
+pub struct KernelStack {
+    name: &'static str,
+    bottom: VirtAddr,
+    top: VirtAddr,
+}
+
+// Allocates a stack of `size` bytes (rounded up to pages) plus its guard page
+pub fn allocate(name: &'static str, size: u64) -> Result<KernelStack, VmmError> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let size = size.next_multiple_of(PAGE_SIZE);
+    let bottom = VMM.lock().allocate_guarded(name, size, flags)?;
+    Ok(KernelStack { name, bottom, top: bottom + size })
+}
+
+#[allow(dead_code)]
+impl KernelStack {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // The initial stack pointer, stacks grow down from here
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    pub fn guard_page(&self) -> VirtAddr {
+        self.bottom - PAGE_SIZE
+    }
+
+    // Keeps the stack mapped forever, for stacks the CPU switches to on its own like the
+    // IST stacks. Returns the top.
+    pub fn leak(self) -> VirtAddr {
+        let top = self.top;
+        core::mem::forget(self);
+        top
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let mut vmm = VMM.lock();
+        vmm.unmap(self.bottom).expect("[err: kernel stack was not mapped]");
+        vmm.unmap(self.guard_page()).expect("[err: kernel stack guard was not reserved]");
+    }
+}
+
+// The name of the stack whose guard page contains addr, if any.
+// Safe to call from fault handlers, gives up if the VMM is locked.
+pub fn guard_hit(addr: VirtAddr) -> Option<&'static str> {
+    let vmm = VMM.try_lock()?;
+    vmm.find(addr)
+        .filter(|region| region.backing == Backing::Guard)
+        .map(|region| region.name)
+}
+
+#[test_case]
+fn test_kernel_stack_guard() {
+    let stack = allocate("test stack", 3 * PAGE_SIZE).expect("stack allocation failed");
+    assert_eq!(stack.top() - stack.bottom(), 3 * PAGE_SIZE);
+
+    unsafe {
+        stack.bottom().as_mut_ptr::<u64>().write_volatile(1);
+        (stack.top() - 8u64).as_mut_ptr::<u64>().write_volatile(2);
+    }
+    assert_eq!(guard_hit(stack.guard_page() + 8u64), Some("test stack"));
+    assert_eq!(guard_hit(stack.bottom()), None);
+
+    let guard = stack.guard_page();
+    drop(stack);
+    assert_eq!(guard_hit(guard), None);
+}
+
+#[test_case]
+fn test_boot_stack_registered() {
+    let marker = 0u8;
+    let vmm = VMM.lock();
+    let region = vmm.find(VirtAddr::from_ptr(&marker)).expect("boot stack not registered");
+    assert_eq!(region.name, "boot stack");
+    let guard = vmm.find(region.start - 1u64).expect("boot stack has no guard page");
+    assert_eq!((guard.name, guard.backing), ("boot stack", Backing::Guard));
+}
@@ -0,0 +1,533 @@
+// Kernel virtual address space manager.
+//
+// Every virtual range the kernel uses is recorded as a named region. Fixed ranges (the heap,
+// the bootloader's mappings) are reserved at boot, everything else is allocated from the
+// dynamic window and backed by fresh frames.
+//
+// The region table is a fixed size array so it works before the heap exists.
+// Lock order is VMM -> MAPPER -> FRAME_ALLOCATOR, and never allocate on the heap while
+// holding VMM.
+
+use x86_64::{
+    PhysAddr, VirtAddr, structures::paging::{
+        mapper::{MapToError, UnmapError}, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+        PhysFrame, Size4KiB, Translate
+    },
+    structures::idt::PageFaultErrorCode,
+};
+use bootloader::bootinfo::MemoryMap;
+use super::{
+    allocator::{Locked, HEAP_START, HEAP_MAX_SIZE},
+    frame_allocator::FRAME_ALLOCATOR,
+    page_table::MAPPER,
+};
+use crate::serial_println;
+
+const PAGE_SIZE: u64 = 4096;
+const MAX_REGIONS: usize = 64;
+const L4_ENTRY_SHIFT: u64 = 39; // every level 4 entry covers 512 GiB
+
+// The window allocate() hands out addresses from
+pub const DYNAMIC_START: u64 = 0x_6666_0000_0000;
+pub const DYNAMIC_SIZE: u64 = 1 << 40; // 1 TiB
+
+pub static VMM: Locked<AddressSpace> = Locked::new(AddressSpace::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    Reserved, // only recorded, whoever reserved it does the mapping
+    Frames,   // mapped to frames owned by the region, which go back to the frame allocator on unmap
+    Physical(PhysAddr), // mapped to a fixed physical range like device memory, the frames are not ours
+    Guard,    // never mapped, sits below a stack so an overflow faults instead of corrupting memory
+    Lazy,     // pages are mapped to zeroed frames owned by the region on first touch
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub size: u64,
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+}
+
+impl Region {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    fn overlaps(&self, start: VirtAddr, size: u64) -> bool {
+        self.start < start + size && start < self.end()
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum VmmError {
+    Overlap(&'static str), // the name of the region in the way
+    OutOfAddressSpace,
+    TooManyRegions,
+    NotFound,
+    Map(MapToError<Size4KiB>),
+}
+
+pub struct AddressSpace {
+    regions: [Option<Region>; MAX_REGIONS], // sorted by start address, unused slots at the end
+    count: usize,
+}
+
+impl AddressSpace {
+    pub const fn new() -> Self {
+        AddressSpace {
+            regions: [None; MAX_REGIONS],
+            count: 0,
+        }
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = &Region> {
+        self.regions[..self.count].iter().flatten()
+    }
+
+    // The region containing addr, if any
+    #[allow(dead_code)]
+    pub fn find(&self, addr: VirtAddr) -> Option<&Region> {
+        self.regions().find(|region| region.contains(addr))
+    }
+
+    // Records a fixed range without mapping anything
+    pub fn reserve(&mut self, name: &'static str, start: VirtAddr, size: u64, flags: PageTableFlags)
+        -> Result<VirtAddr, VmmError>
+    {
+        assert!(start.is_aligned(PAGE_SIZE) && size.is_multiple_of(PAGE_SIZE) && size > 0);
+        self.insert(Region { name, start, size, flags, backing: Backing::Reserved })
+    }
+
+    // Picks a free range of `size` bytes (rounded up to pages) in the dynamic window and
+    // maps it to fresh frames
+    #[allow(dead_code)]
+    pub fn allocate(&mut self, name: &'static str, size: u64, flags: PageTableFlags)
+        -> Result<VirtAddr, VmmError>
+    {
+        assert!(size > 0);
+        if self.count == MAX_REGIONS {
+            return Err(VmmError::TooManyRegions);
+        }
+        let size = size.next_multiple_of(PAGE_SIZE);
+        let start = self.find_free(size)?;
+
+        map_range(start, size, flags).map_err(VmmError::Map)?;
+        self.insert(Region { name, start, size, flags, backing: Backing::Frames })
+    }
+
+    // Like allocate, but with an unmapped guard page right below the mapped range, which
+    // gets the same name. Returns the start of the mapped range.
+    pub fn allocate_guarded(&mut self, name: &'static str, size: u64, flags: PageTableFlags)
+        -> Result<VirtAddr, VmmError>
+    {
+        assert!(size > 0);
+        if self.count + 2 > MAX_REGIONS {
+            return Err(VmmError::TooManyRegions);
+        }
+        let size = size.next_multiple_of(PAGE_SIZE);
+        let guard = self.find_free(PAGE_SIZE + size)?;
+        let start = guard + PAGE_SIZE;
+
+        map_range(start, size, flags).map_err(VmmError::Map)?;
+        self.insert(Region { name, start: guard, size: PAGE_SIZE, flags: PageTableFlags::empty(), backing: Backing::Guard })?;
+        self.insert(Region { name, start, size, flags, backing: Backing::Frames })
+    }
+
+    // Picks a free range in the dynamic window without mapping anything, pages are mapped
+    // on demand by handle_demand_fault when they are first touched
+    #[allow(dead_code)]
+    pub fn allocate_lazy(&mut self, name: &'static str, size: u64, flags: PageTableFlags)
+        -> Result<VirtAddr, VmmError>
+    {
+        assert!(size > 0);
+        let size = size.next_multiple_of(PAGE_SIZE);
+        let start = self.find_free(size)?;
+        self.insert(Region { name, start, size, flags, backing: Backing::Lazy })
+    }
+
+    // Picks a free range in the dynamic window and maps it to the physical range starting at
+    // the page aligned `phys`. The frames are never handed to the frame allocator.
+    #[allow(dead_code)]
+    pub fn map_physical(&mut self, name: &'static str, phys: PhysAddr, size: u64, flags: PageTableFlags)
+        -> Result<VirtAddr, VmmError>
+    {
+        assert!(phys.is_aligned(PAGE_SIZE) && size > 0);
+        if self.count == MAX_REGIONS {
+            return Err(VmmError::TooManyRegions);
+        }
+        let size = size.next_multiple_of(PAGE_SIZE);
+        let start = self.find_free(size)?;
+
+        map_physical_range(start, phys, size, flags).map_err(VmmError::Map)?;
+        self.insert(Region { name, start, size, flags, backing: Backing::Physical(phys) })
+    }
+
+    // Removes the region starting at `start`. Pages the region owns are unmapped and their
+    // frames freed. Returns the number of frames freed.
+    #[allow(dead_code)]
+    pub fn unmap(&mut self, start: VirtAddr) -> Result<usize, VmmError> {
+        let index = self.regions().position(|region| region.start == start)
+            .ok_or(VmmError::NotFound)?;
+        let region = self.remove(index);
+
+        Ok(match region.backing {
+            Backing::Frames => unmap_range(region.start, region.size),
+            Backing::Physical(_) => {
+                unmap_pages(region.start, region.size, false);
+                0
+            }
+            Backing::Lazy => unmap_touched_pages(region.start, region.size),
+            Backing::Reserved | Backing::Guard => 0,
+        })
+    }
+
+    // First fit over the gaps between the regions inside the dynamic window
+    fn find_free(&self, size: u64) -> Result<VirtAddr, VmmError> {
+        let window_end = DYNAMIC_START + DYNAMIC_SIZE;
+        let mut candidate = DYNAMIC_START;
+        for region in self.regions() {
+            let (start, end) = (region.start.as_u64(), region.end().as_u64());
+            if end <= candidate {
+                continue;
+            }
+            if start >= candidate + size {
+                break;
+            }
+            candidate = end;
+        }
+
+        if candidate + size <= window_end {
+            Ok(VirtAddr::new(candidate))
+        } else {
+            Err(VmmError::OutOfAddressSpace)
+        }
+    }
+
+    // Reserves whatever parts of start..start + size no region covers yet
+    fn reserve_gaps(&mut self, name: &'static str, start: VirtAddr, size: u64, flags: PageTableFlags)
+        -> Result<(), VmmError>
+    {
+        let end = start + size;
+        let mut gap_start = start;
+        while gap_start < end {
+            let next = self.regions().find(|region| region.end() > gap_start && region.start < end).copied();
+            let gap_end = next.map_or(end, |region| region.start.max(gap_start));
+            if gap_end > gap_start {
+                self.reserve(name, gap_start, gap_end - gap_start, flags)?;
+            }
+            gap_start = next.map_or(end, |region| region.end());
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, region: Region) -> Result<VirtAddr, VmmError> {
+        if let Some(other) = self.regions().find(|other| other.overlaps(region.start, region.size)) {
+            return Err(VmmError::Overlap(other.name));
+        }
+        if self.count == MAX_REGIONS {
+            return Err(VmmError::TooManyRegions);
+        }
+
+        let index = self.regions().take_while(|other| other.start < region.start).count();
+        self.regions.copy_within(index..self.count, index + 1);
+        self.regions[index] = Some(region);
+        self.count += 1;
+        Ok(region.start)
+    }
+
+    fn remove(&mut self, index: usize) -> Region {
+        let region = self.regions[index].take().unwrap();
+        self.regions.copy_within(index + 1..self.count, index);
+        self.count -= 1;
+        self.regions[self.count] = None;
+        region
+    }
+}
+
+// Reserves the heap, the physical memory map and whatever else the bootloader mapped.
+// Needs MAPPER to be initialized and must run before the heap is mapped.
+pub fn init(memory_map: &MemoryMap) {
+    let mut vmm = VMM.lock();
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().expect("[err: mapper not initialized]");
+    let writable = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let phys_size = memory_map.iter()
+        .map(|r| r.range.end_addr())
+        .max()
+        .unwrap_or(0)
+        .next_multiple_of(PAGE_SIZE);
+    vmm.reserve("physical memory", mapper.phys_offset(), phys_size, writable)
+        .expect("[err: could not reserve the physical memory map]");
+    vmm.reserve("heap", VirtAddr::new(HEAP_START as u64), HEAP_MAX_SIZE as u64, writable)
+        .expect("[err: could not reserve the heap]");
+
+    // The bootloader leaves the page below the boot stack unmapped, so the stack is
+    // everything mapped around the current stack pointer
+    let stack_marker = 0u8;
+    let mut bottom = Page::<Size4KiB>::containing_address(VirtAddr::from_ptr(&stack_marker));
+    let mut top = bottom + 1;
+    while mapper.translate_addr((bottom - 1).start_address()).is_some() {
+        bottom -= 1;
+    }
+    while mapper.translate_addr(top.start_address()).is_some() {
+        top += 1;
+    }
+    let guard = (bottom - 1).start_address();
+    vmm.insert(Region { name: "boot stack", start: guard, size: PAGE_SIZE, flags: PageTableFlags::empty(), backing: Backing::Guard })
+        .expect("[err: could not reserve the boot stack]");
+    vmm.reserve("boot stack", bottom.start_address(), top.start_address() - bottom.start_address(), writable)
+        .expect("[err: could not reserve the boot stack]");
+
+    // The kernel image and boot info are not described anywhere, so the rest of every
+    // level 4 entry in use is kept off limits as a whole
+    let entry_size = 1 << L4_ENTRY_SHIFT;
+    for (index, entry) in mapper.level_4_table().iter().enumerate() {
+        if entry.is_unused() {
+            continue;
+        }
+        // The last entry of each half leaves out its last page, so region ends stay canonical
+        let start = VirtAddr::new_truncate((index as u64) << L4_ENTRY_SHIFT);
+        let size = if index == 255 || index == 511 { entry_size - PAGE_SIZE } else { entry_size };
+        vmm.reserve_gaps("boot mappings", start, size, entry.flags())
+            .expect("[err: could not reserve the boot mappings]");
+    }
+}
+
+// Called by the page fault handler. Maps a zeroed frame for a not present page inside a lazy
+// region and returns true, so the faulting access can be retried. Returns false when this is
+// no demand fault, or when the interrupted code holds one of the locks needed to map the page.
+pub fn handle_demand_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if error_code.intersects(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::MALFORMED_TABLE) {
+        return false;
+    }
+    let Some(vmm) = VMM.try_lock() else { return false };
+    let Some(region) = vmm.find(addr).filter(|region| region.backing == Backing::Lazy) else {
+        return false;
+    };
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && !region.flags.contains(PageTableFlags::WRITABLE) {
+        return false;
+    }
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) && region.flags.contains(PageTableFlags::NO_EXECUTE) {
+        return false;
+    }
+
+    let (Some(mut mapper), Some(mut frame_allocator)) = (MAPPER.try_lock(), FRAME_ALLOCATOR.try_lock()) else {
+        return false;
+    };
+    let Some(mapper) = mapper.as_mut() else { return false };
+    let Some(frame) = frame_allocator.allocate_frame() else { return false };
+
+    // Zeroed through the physical memory map, the region may not be writable
+    let frame_virt = mapper.phys_offset() + frame.start_address().as_u64();
+    unsafe { frame_virt.as_mut_ptr::<u8>().write_bytes(0, PAGE_SIZE as usize) };
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+    match unsafe { mapper.map_to(page, frame, region.flags, &mut *frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            false
+        }
+    }
+}
+
+// Prints the region map to serial
+#[allow(dead_code)]
+pub fn dump_regions() {
+    let vmm = VMM.lock();
+    serial_println!("Kernel address space ({} regions):", vmm.count);
+    for region in vmm.regions() {
+        serial_println!("    {:#018x}..{:#018x} {:>12} KiB {:<8?} {:<16} {:?}",
+            region.start.as_u64(), region.end().as_u64(), region.size / 1024,
+            region.backing, region.name, region.flags);
+    }
+}
+
+// Maps start..start + size to fresh frames, undoing everything if that fails so no frames
+// leak. Does not touch the region table, the heap grows through this while VMM may be held.
+pub fn map_range(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().expect("[err: mapper not initialized]");
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+
+    for (mapped, page) in page_range(start, size).enumerate() {
+        let frame = frame_allocator.allocate_frame();
+        let result = match frame {
+            Some(frame) => unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator) },
+            None => Err(MapToError::FrameAllocationFailed),
+        };
+        match result {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                if let Some(frame) = frame {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+                for page in page_range(start, mapped as u64 * PAGE_SIZE) {
+                    let (frame, flush) = mapper.unmap(page).expect("[err: page was not mapped]");
+                    flush.flush();
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Maps start..start + size to the physical range at phys, only page tables are allocated
+fn map_physical_range(start: VirtAddr, phys: PhysAddr, size: u64, flags: PageTableFlags)
+    -> Result<(), MapToError<Size4KiB>>
+{
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().expect("[err: mapper not initialized]");
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+
+    for (mapped, page) in page_range(start, size).enumerate() {
+        let frame = PhysFrame::<Size4KiB>::containing_address(phys + mapped as u64 * PAGE_SIZE);
+        match unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                for page in page_range(start, mapped as u64 * PAGE_SIZE) {
+                    mapper.unmap(page).expect("[err: page was not mapped]").1.flush();
+                }
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Unmaps start..start + size and frees the frames behind it. Returns the number of frames freed.
+pub fn unmap_range(start: VirtAddr, size: u64) -> usize {
+    unmap_pages(start, size, true)
+}
+
+// Like unmap_range, but skips the pages of a lazy region that were never touched
+fn unmap_touched_pages(start: VirtAddr, size: u64) -> usize {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().expect("[err: mapper not initialized]");
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+
+    let mut freed = 0;
+    for page in page_range(start, size) {
+        match mapper.unmap(page) {
+            Ok((frame, flush)) => {
+                flush.flush();
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                freed += 1;
+            }
+            Err(UnmapError::PageNotMapped) => {}
+            Err(err) => panic!("[err: could not unmap lazy page {:?}: {:?}]", page, err),
+        }
+    }
+    freed
+}
+
+fn unmap_pages(start: VirtAddr, size: u64, free_frames: bool) -> usize {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().expect("[err: mapper not initialized]");
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+
+    let mut freed = 0;
+    for page in page_range(start, size) {
+        let (frame, flush) = mapper.unmap(page).expect("[err: page was not mapped]");
+        flush.flush();
+        if free_frames {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            freed += 1;
+        }
+    }
+    freed
+}
+
+fn page_range(start: VirtAddr, size: u64) -> impl Iterator<Item = Page<Size4KiB>> {
+    let start_page = Page::containing_address(start);
+    let end_page = Page::containing_address(start + size);
+    Page::range(start_page, end_page)
+}
+
+#[test_case]
+fn test_vmm_allocate_and_unmap() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let start = VMM.lock().allocate("test", 3 * PAGE_SIZE, flags).expect("vmm allocation failed");
+    assert!(start.as_u64() >= DYNAMIC_START);
+    assert_eq!(VMM.lock().find(start + 2 * PAGE_SIZE).map(|region| region.name), Some("test"));
+
+    for page in 0..3 {
+        let ptr = (start + page * PAGE_SIZE).as_mut_ptr::<u64>();
+        unsafe {
+            ptr.write_volatile(page);
+            assert_eq!(ptr.read_volatile(), page);
+        }
+    }
+
+    // Page tables created on the way stay mapped, only the region's own frames come back
+    let frames_before = FRAME_ALLOCATOR.lock().free_frames();
+    assert_eq!(VMM.lock().unmap(start).unwrap(), 3);
+    assert_eq!(FRAME_ALLOCATOR.lock().free_frames(), frames_before + 3);
+    assert!(VMM.lock().find(start).is_none());
+}
+
+#[test_case]
+fn test_vmm_regions_do_not_overlap() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut vmm = VMM.lock();
+    let heap = VirtAddr::new(HEAP_START as u64);
+    assert!(matches!(vmm.reserve("clash", heap, PAGE_SIZE, flags), Err(VmmError::Overlap("heap"))));
+
+    let first = vmm.allocate("first", PAGE_SIZE, flags).unwrap();
+    let second = vmm.allocate("second", 2 * PAGE_SIZE, flags).unwrap();
+    assert!(second >= first + PAGE_SIZE || second + 2 * PAGE_SIZE <= first);
+
+    // A hole left by an unmapped region is reused
+    vmm.unmap(first).unwrap();
+    let third = vmm.allocate("third", PAGE_SIZE, flags).unwrap();
+    assert_eq!(third, first);
+    vmm.unmap(second).unwrap();
+    vmm.unmap(third).unwrap();
+}
+
+#[test_case]
+fn test_vmm_demand_zero() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let start = VMM.lock().allocate_lazy("lazy test", 4 * PAGE_SIZE, flags).expect("vmm allocation failed");
+
+    // The first touch of each page faults and maps a zeroed frame
+    let frames_before = FRAME_ALLOCATOR.lock().free_frames();
+    let second = (start + PAGE_SIZE).as_mut_ptr::<u64>();
+    unsafe {
+        assert_eq!(second.read_volatile(), 0);
+        second.write_volatile(0xfeed);
+        assert_eq!(second.read_volatile(), 0xfeed);
+        assert_eq!((start + 3 * PAGE_SIZE).as_ptr::<u64>().read_volatile(), 0);
+    }
+    assert!(FRAME_ALLOCATOR.lock().free_frames() < frames_before);
+
+    // Only the two touched pages had frames behind them
+    assert_eq!(VMM.lock().unmap(start).unwrap(), 2);
+}
+
+#[test_case]
+fn test_map_range_failure_returns_frames() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let start = VMM.lock().allocate_lazy("map failure test", 2 * PAGE_SIZE, flags).expect("vmm allocation failed");
+    unsafe { (start + PAGE_SIZE).as_mut_ptr::<u64>().write_volatile(1) };
+
+    // The first page maps, the second is already mapped, so both frames have to come back
+    let frames_before = FRAME_ALLOCATOR.lock().free_frames();
+    assert!(matches!(map_range(start, 2 * PAGE_SIZE, flags), Err(MapToError::PageAlreadyMapped(_))));
+    assert_eq!(FRAME_ALLOCATOR.lock().free_frames(), frames_before);
+    assert_eq!(VMM.lock().unmap(start).unwrap(), 1);
+}
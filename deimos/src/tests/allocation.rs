@@ -0,0 +1,218 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::alloc::Layout;
+use crate::memory_management::allocator::{self, HEAP_MAX_SIZE};
+use crate::serial_println;
+use deimos_alloc::linked_list::{LinkedListAllocator, Placement};
+
+// Fills the heap with many blocks, frees them and checks they coalesce back into
+// a region big enough for one allocation of their combined size
+#[test_case]
+fn test_coalescence() {
+    let chunk_size = 4096; // above the slab size classes, so the linked list does the work
+    let iterations = 875;
+    let mut chunks = Vec::new();
+
+    for _ in 0..iterations {
+        let mut v = Vec::<u8>::with_capacity(chunk_size);
+        v.push(1);
+        chunks.push(v);
+    }
+
+    drop(chunks);
+
+    // Growing is not allowed, the giant block has to come from the coalesced chunks
+    allocator::set_heap_limit(allocator::heap_size());
+    let giant_layout = Layout::from_size_align(chunk_size * iterations, 8).unwrap();
+    unsafe {
+        let ptr = alloc::alloc::alloc(giant_layout);
+
+        if ptr.is_null() {
+            panic!("Fragmentation Test Failed: Could not coalesce blocks!");
+        } else {
+            alloc::alloc::dealloc(ptr, giant_layout);
+        }
+    }
+    allocator::set_heap_limit(HEAP_MAX_SIZE);
+}
+
+#[test_case]
+fn test_alignment() {
+    let mut align = 1;
+    while align <= 4096 {
+        for size in [1, align / 2 + 1, align, align * 3] {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            unsafe {
+                let ptr = alloc::alloc::alloc(layout);
+                assert!(!ptr.is_null());
+                assert_eq!(ptr as usize % align, 0, "size {} align {}", size, align);
+                ptr.write_bytes(0xab, size);
+                alloc::alloc::dealloc(ptr, layout);
+            }
+        }
+        align *= 2;
+    }
+}
+
+// Zero sized types never reach the allocator
+#[test_case]
+fn test_zero_size() {
+    let before = allocator::stats().allocations;
+
+    let unit = Box::new(());
+    let mut units = Vec::<()>::new();
+    for _ in 0..1000 {
+        units.push(());
+    }
+    let empty = Vec::<u64>::with_capacity(0);
+
+    assert_eq!(allocator::stats().allocations, before);
+    assert_eq!(units.len(), 1000);
+    drop((unit, units, empty));
+}
+
+#[test_case]
+fn test_realloc_grow_and_shrink() {
+    let mut v = Vec::<u64>::new();
+    for i in 0..10_000 {
+        v.push(i);
+    }
+    assert!(v.iter().enumerate().all(|(i, &x)| x == i as u64));
+
+    v.truncate(10);
+    v.shrink_to_fit();
+    assert_eq!(v.capacity(), 10);
+    assert_eq!(v, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+
+    v.reserve(100_000);
+    assert_eq!(v[9], 9);
+}
+
+// Growing a big Vec resizes it in place, so the old and new buffer never exist at once
+#[cfg(not(feature = "heap_debug"))]
+#[test_case]
+fn test_realloc_in_place_peak_usage() {
+    // Only the largest free region fits, which is the one running up to the end of the heap
+    let initial = (allocator::stats().largest_free_block - 4096) & !4095;
+    let mut v = Vec::<u8>::with_capacity(initial);
+    let start = v.as_ptr();
+    allocator::reset_peak_usage();
+    let in_use = allocator::stats().bytes_in_use;
+
+    for _ in 0..3 {
+        v.reserve_exact(v.capacity());
+        v.resize(v.capacity(), 0xaa);
+    }
+    assert_eq!(v.as_ptr(), start, "the Vec was moved");
+    assert!(allocator::stats().peak_usage <= in_use - initial + v.capacity());
+}
+
+#[test_case]
+fn test_interleaved_frees() {
+    let mut boxes: Vec<Option<Box<[u8; 200]>>> = (0..1000)
+        .map(|i| Some(Box::new([i as u8; 200])))
+        .collect();
+
+    // Free every other box, then fill the holes with different sizes
+    for slot in boxes.iter_mut().step_by(2) {
+        *slot = None;
+    }
+    let fillers: Vec<Vec<u8>> = (0..500).map(|i| alloc::vec![0xee; 50 + i % 150]).collect();
+
+    for (i, slot) in boxes.iter().enumerate() {
+        if let Some(b) = slot {
+            assert!(b.iter().all(|&x| x == i as u8), "box {} was overwritten", i);
+        }
+    }
+    assert!(fillers.iter().all(|f| f.iter().all(|&x| x == 0xee)));
+}
+
+// Caps the heap at its current size, allocates until that fails and checks that
+// the heap is usable again once everything is freed
+#[test_case]
+fn test_exhaustion_and_recovery() {
+    let chunk = Layout::from_size_align(64 * 1024, 8).unwrap();
+    let mut chunks = Vec::with_capacity(4096);
+    let failed_before = allocator::stats().failed_allocations;
+
+    allocator::set_heap_limit(allocator::heap_size());
+    loop {
+        let ptr = unsafe { alloc::alloc::alloc(chunk) };
+        if ptr.is_null() {
+            break;
+        }
+        chunks.push(ptr);
+        assert!(chunks.len() < chunks.capacity(), "heap limit was not enforced");
+    }
+    assert!(allocator::stats().failed_allocations > failed_before);
+
+    for ptr in chunks.drain(..) {
+        unsafe { alloc::alloc::dealloc(ptr, chunk) };
+    }
+    let ptr = unsafe { alloc::alloc::alloc(chunk) };
+    assert!(!ptr.is_null());
+    unsafe { alloc::alloc::dealloc(ptr, chunk) };
+
+    allocator::set_heap_limit(HEAP_MAX_SIZE);
+}
+
+// Runs the same fragmenting workload against a private allocator for every placement
+// policy, checks every allocation in the fragmented heap lands where the policy says and
+// reports how fragmented each one leaves its heap
+#[test_case]
+fn test_placement_fragmentation() {
+    const ARENA: usize = 2 << 20;
+    const BLOCKS: usize = 1024;
+    let arena_layout = Layout::from_size_align(ARENA, 4096).unwrap();
+    let arena = unsafe { alloc::alloc::alloc(arena_layout) };
+    assert!(!arena.is_null());
+
+    // Multiples of 16, so no block needs padding and every region starts 16 byte aligned
+    let layout = |i: usize| Layout::from_size_align(64 + (i * 7919) % 1024 / 16 * 16, 8).unwrap();
+
+    serial_println!();
+    for placement in [Placement::FirstFit, Placement::BestFit] {
+        let mut heap = LinkedListAllocator::with_placement(placement);
+        unsafe { heap.init(arena as usize, ARENA) };
+
+        let mut blocks: Vec<Option<*mut u8>> = (0..BLOCKS).map(|i| {
+            let ptr = unsafe { heap.allocate(layout(i)) };
+            assert!(!ptr.is_null(), "{:?} ran out of memory", placement);
+            Some(ptr)
+        }).collect();
+        // Keep every fifth block, free the rest in a scattered order (31 is coprime to BLOCKS)
+        for i in (0..BLOCKS).map(|k| k * 31 % BLOCKS).filter(|i| i % 5 != 0) {
+            unsafe { heap.deallocate(blocks[i].take().unwrap(), layout(i)) };
+        }
+
+        let start = unsafe { core::arch::x86_64::_rdtsc() };
+        for (i, block) in blocks.iter_mut().enumerate().filter(|(_, block)| block.is_none()) {
+            let size = layout(i + 1).size();
+            // A region fits exactly or with room for a list node behind the allocation
+            let fits = |&(_, region): &(usize, usize)| region == size || region >= size + 16;
+            let expected = match placement {
+                Placement::FirstFit => heap.free_regions().find(fits),
+                Placement::BestFit => heap.free_regions().filter(fits).min_by_key(|&(_, region)| region),
+            };
+
+            let ptr = unsafe { heap.allocate(layout(i + 1)) };
+            assert_eq!(Some(ptr as usize), expected.map(|(addr, _)| addr),
+                "{:?} took the wrong region", placement);
+            *block = Some(ptr);
+        }
+        let cycles = unsafe { core::arch::x86_64::_rdtsc() } - start;
+
+        let free: usize = heap.free_regions().map(|(_, size)| size).sum();
+        let largest = heap.free_regions().map(|(_, size)| size).max().unwrap_or(0);
+        serial_println!("    {:?}: {} cycles, {} free regions, largest {} of {} free bytes",
+            placement, cycles, heap.free_regions().count(), largest, free);
+
+        // Everything coalesces back into one region no matter the policy
+        for (i, block) in blocks.iter().enumerate() {
+            let size = if i % 5 == 0 { layout(i) } else { layout(i + 1) };
+            unsafe { heap.deallocate(block.unwrap(), size) };
+        }
+        assert_eq!(heap.free_regions().count(), 1);
+    }
+
+    unsafe { alloc::alloc::dealloc(arena, arena_layout) };
+}
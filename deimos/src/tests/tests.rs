@@ -3,9 +3,7 @@ use crate::emulation::qemu::{exit_qemu, QemuExitCode};
 
 #[cfg(test)]
 pub fn test_runner(tests: &[&dyn Testable]) -> ! {
-
-    crate::init();
-
+    // The kernel is already initialized by test_main, which has the boot info
     serial_println!("Running {} test(s)", tests.len());
     for test in tests {
         test.run();
@@ -0,0 +1,197 @@
+// Kernel time, counted in ticks of the timer interrupt (interrupts::TIMER_HZ a second).
+//
+// The tick count starts at 0 when interrupts are enabled at boot and only goes up. sleep
+// halts the CPU between ticks instead of spinning, and timers call a function from the timer
+// interrupt once their tick has come. Instant measures time at nanosecond resolution with the
+// TSC once tsc::init calibrated it, and in whole ticks before that or without one.
+
+pub mod tsc;
+
+use core::{ops::{Add, Sub}, sync::atomic::{AtomicU64, Ordering}, time::Duration};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::hardware_interface::interrupts::TIMER_HZ;
+
+const MAX_TIMERS: usize = 32;
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static TIMERS: Mutex<[Option<Timer>; MAX_TIMERS]> = Mutex::new([None; MAX_TIMERS]);
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy)]
+struct Timer {
+    id: TimerId,
+    deadline: u64,
+    callback: fn(),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    TooManyTimers,
+}
+
+// Ticks since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+// Time since boot, in steps of one tick
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks())
+}
+
+// Halts until at least duration has passed. Needs interrupts enabled, or no tick would
+// ever wake the CPU up again.
+#[allow(dead_code)]
+pub fn sleep(duration: Duration) {
+    sleep_ticks(duration_to_ticks(duration));
+}
+
+// Halts until count more ticks have happened, so it returns right after a tick
+pub fn sleep_ticks(count: u64) {
+    assert!(interrupts::are_enabled(), "sleep with interrupts disabled would never wake up");
+    let deadline = ticks() + count;
+    loop {
+        // Checking with interrupts off and enabling them together with hlt makes sure the
+        // tick that reaches the deadline can not slip in between and leave us halted
+        interrupts::disable();
+        if ticks() >= deadline {
+            interrupts::enable();
+            return;
+        }
+        interrupts::enable_and_hlt();
+    }
+}
+
+// Calls callback from the timer interrupt at tick deadline, or on the next tick if that has
+// passed already. The callback runs with interrupts disabled, so it must be short and must
+// not wait for locks or allocate.
+pub fn call_at(deadline: u64, callback: fn()) -> Result<TimerId, TimerError> {
+    let id = TimerId(NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed));
+    interrupts::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let slot = timers.iter_mut().find(|slot| slot.is_none()).ok_or(TimerError::TooManyTimers)?;
+        *slot = Some(Timer { id, deadline, callback });
+        Ok(id)
+    })
+}
+
+// Calls callback once delay has passed, see call_at
+#[allow(dead_code)]
+pub fn call_after(delay: Duration, callback: fn()) -> Result<TimerId, TimerError> {
+    call_at(ticks() + duration_to_ticks(delay), callback)
+}
+
+// Removes a timer that has not fired yet. Returns whether it was still pending.
+#[allow(dead_code)]
+pub fn cancel(id: TimerId) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let slot = timers.iter_mut().find(|slot| slot.is_some_and(|timer| timer.id == id));
+        slot.map(|slot| slot.take()).is_some()
+    })
+}
+
+// Called by the timer interrupt handler on every tick
+pub fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+
+    // The callbacks run after the lock is released, so they may register new timers
+    let mut due = [None; MAX_TIMERS];
+    let Some(mut timers) = TIMERS.try_lock() else { return };
+    for (slot, due) in timers.iter_mut().zip(due.iter_mut()) {
+        if slot.is_some_and(|timer| timer.deadline <= now) {
+            *due = slot.take().map(|timer| timer.callback);
+        }
+    }
+    drop(timers);
+    for callback in due.into_iter().flatten() {
+        callback();
+    }
+}
+
+// A point in monotonic time, like std::time::Instant
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64); // nanoseconds since boot
+
+#[allow(dead_code)]
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(tsc::nanos().unwrap_or_else(|| uptime().as_nanos() as u64))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now() - *self
+    }
+
+    // Zero if earlier is in fact later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0 + duration.as_nanos() as u64)
+    }
+}
+
+// Rounded up, so waiting for this many ticks takes at least duration
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    (duration.as_nanos() * TIMER_HZ as u128).div_ceil(NANOS_PER_SECOND) as u64
+}
+
+fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_nanos((ticks as u128 * NANOS_PER_SECOND / TIMER_HZ as u128) as u64)
+}
+
+#[test_case]
+fn test_sleep() {
+    let start = ticks();
+    sleep(Duration::from_millis(30));
+    assert!(ticks() - start >= duration_to_ticks(Duration::from_millis(30)));
+    assert_eq!(duration_to_ticks(Duration::from_millis(1)), 1);
+    assert_eq!(ticks_to_duration(TIMER_HZ), Duration::from_secs(1));
+}
+
+#[test_case]
+fn test_timer_callbacks() {
+    use core::sync::atomic::AtomicBool;
+    static FIRED: AtomicBool = AtomicBool::new(false);
+    static CANCELLED_FIRED: AtomicBool = AtomicBool::new(false);
+
+    call_after(Duration::from_millis(20), || FIRED.store(true, Ordering::Relaxed)).unwrap();
+    let cancelled = call_after(Duration::from_millis(20), || CANCELLED_FIRED.store(true, Ordering::Relaxed)).unwrap();
+    assert!(cancel(cancelled));
+    assert!(!cancel(cancelled));
+
+    sleep(Duration::from_millis(50));
+    assert!(FIRED.load(Ordering::Relaxed));
+    assert!(!CANCELLED_FIRED.load(Ordering::Relaxed));
+}
+
+#[test_case]
+fn test_instant() {
+    let start = Instant::now();
+    sleep(Duration::from_millis(20));
+    let elapsed = start.elapsed();
+    // The sleep ends on the second tick boundary at the earliest
+    assert!(elapsed >= Duration::from_millis(10) && elapsed < Duration::from_secs(1), "{:?}", elapsed);
+    assert!(Instant::now() >= start + elapsed);
+    assert_eq!(start - Instant::now(), Duration::ZERO);
+}
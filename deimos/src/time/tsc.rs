@@ -0,0 +1,122 @@
+// The time stamp counter as a high resolution clock.
+//
+// The TSC counts at a fixed rate on CPUs with an invariant TSC, but that rate is not
+// reported everywhere, so init measures it against PIT channel 2. Until then, and for good
+// when the TSC is not invariant or calibration fails, nanos returns None and the time
+// module counts in timer ticks instead.
+
+use core::{arch::x86_64::{__cpuid, _rdtsc}, fmt, sync::atomic::{AtomicU64, Ordering}};
+use x86_64::instructions::{interrupts, port::Port};
+use crate::hardware_interface::interrupts::PIT_HZ;
+
+const CALIBRATION_MS: u64 = 10;
+const CALIBRATION_RUNS: usize = 3;
+// Gives up on a PIT that never counts down, after roughly a second of port reads
+const MAX_POLLS: usize = 1_000_000;
+
+const INVARIANT_TSC: u32 = 1 << 8;
+const SPEAKER_GATE: u8 = 1 << 0;
+const SPEAKER_DATA: u8 = 1 << 1;
+const CHANNEL_2_OUT: u8 = 1 << 5;
+
+// 0 until a calibration succeeded
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+// The counter and the tick based uptime at calibration, which TSC time continues from
+static BASE_TSC: AtomicU64 = AtomicU64::new(0);
+static BASE_NANOS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TscError {
+    NotInvariant,
+    CalibrationFailed,
+}
+
+impl fmt::Display for TscError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TscError::NotInvariant => write!(f, "the TSC is not invariant"),
+            TscError::CalibrationFailed => write!(f, "PIT channel 2 did not count down"),
+        }
+    }
+}
+
+// Measures the TSC frequency and switches the clock over to it. Returns the frequency in Hz.
+pub fn init() -> Result<u64, TscError> {
+    if !invariant() {
+        return Err(TscError::NotInvariant);
+    }
+    // The shortest run had the least interference from interrupts and the hypervisor
+    let mut cycles = u64::MAX;
+    for _ in 0..CALIBRATION_RUNS {
+        let run = interrupts::without_interrupts(measure).ok_or(TscError::CalibrationFailed)?;
+        cycles = cycles.min(run);
+    }
+    let hz = cycles * 1000 / CALIBRATION_MS;
+
+    interrupts::without_interrupts(|| {
+        BASE_NANOS.store(super::uptime().as_nanos() as u64, Ordering::Relaxed);
+        BASE_TSC.store(read(), Ordering::Relaxed);
+        TSC_HZ.store(hz, Ordering::Release);
+    });
+    Ok(hz)
+}
+
+// The calibrated frequency in Hz
+#[allow(dead_code)]
+pub fn frequency() -> Option<u64> {
+    match TSC_HZ.load(Ordering::Acquire) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+// Nanoseconds since boot, None without a calibrated TSC
+pub fn nanos() -> Option<u64> {
+    let hz = frequency()?;
+    let cycles = read().wrapping_sub(BASE_TSC.load(Ordering::Relaxed));
+    Some(BASE_NANOS.load(Ordering::Relaxed) + (cycles as u128 * 1_000_000_000 / hz as u128) as u64)
+}
+
+// The raw counter, for counting cycles
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+fn invariant() -> bool {
+    let max_extended_leaf = __cpuid(0x8000_0000).eax;
+    max_extended_leaf >= 0x8000_0007 && __cpuid(0x8000_0007).edx & INVARIANT_TSC != 0
+}
+
+// TSC cycles during a CALIBRATION_MS one shot count of PIT channel 2, whose output is
+// readable through the speaker control port
+fn measure() -> Option<u64> {
+    let mut control: Port<u8> = Port::new(0x61);
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_2: Port<u8> = Port::new(0x42);
+    let count = (PIT_HZ * CALIBRATION_MS / 1000) as u16;
+
+    unsafe {
+        let saved = control.read();
+        // Speaker off, the gate stays low until the count is loaded
+        control.write(saved & !(SPEAKER_DATA | SPEAKER_GATE));
+        // Channel 2, low then high byte, mode 0 (interrupt on terminal count)
+        command.write(0b1011_0000);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+
+        // Raising the gate starts the count
+        control.write((saved & !SPEAKER_DATA) | SPEAKER_GATE);
+        let start = read();
+        let mut polls = 0;
+        while control.read() & CHANNEL_2_OUT == 0 {
+            polls += 1;
+            if polls == MAX_POLLS {
+                control.write(saved);
+                return None;
+            }
+        }
+        let end = read();
+        control.write(saved);
+        Some(end - start)
+    }
+}
@@ -0,0 +1,150 @@
+// Compares the placement policies of the linked list allocator on the same workload.
+// Run with `cargo run --release --example placement_bench`.
+//
+// The workload mimics a long running kernel: mostly short lived small and medium
+// allocations with some long lived ones mixed in, which is what fragments a heap. It runs
+// on the bare linked list and on the fixed size block allocator in front of it, which is
+// what the kernel uses.
+
+use std::alloc::{alloc, Layout};
+use std::time::Instant;
+
+use deimos_alloc::fixed_size_block::FixedSizeBlockAllocator;
+use deimos_alloc::linked_list::{LinkedListAllocator, Placement};
+
+const HEAP_SIZE: usize = 16 * 1024 * 1024;
+const OPERATIONS: usize = 200_000;
+
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, bound: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % bound as u64) as usize
+    }
+}
+
+trait Heap {
+    fn new(placement: Placement) -> Self;
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize);
+    unsafe fn allocate(&mut self, layout: Layout) -> *mut u8;
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout);
+    fn free_regions(&self) -> Vec<usize>;
+}
+
+impl Heap for LinkedListAllocator {
+    fn new(placement: Placement) -> Self {
+        LinkedListAllocator::with_placement(placement)
+    }
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        unsafe { LinkedListAllocator::init(self, heap_start, heap_size) }
+    }
+    unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        unsafe { LinkedListAllocator::allocate(self, layout) }
+    }
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        unsafe { LinkedListAllocator::deallocate(self, ptr, layout) }
+    }
+    fn free_regions(&self) -> Vec<usize> {
+        LinkedListAllocator::free_regions(self).map(|(_, size)| size).collect()
+    }
+}
+
+impl Heap for FixedSizeBlockAllocator {
+    fn new(placement: Placement) -> Self {
+        FixedSizeBlockAllocator::with_placement(placement)
+    }
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        unsafe { FixedSizeBlockAllocator::init(self, heap_start, heap_size) }
+    }
+    unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        unsafe { FixedSizeBlockAllocator::allocate(self, layout) }
+    }
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        unsafe { FixedSizeBlockAllocator::deallocate(self, ptr, layout) }
+    }
+    fn free_regions(&self) -> Vec<usize> {
+        FixedSizeBlockAllocator::free_regions(self).map(|(_, size)| size).collect()
+    }
+}
+
+struct Report {
+    nanos_per_op: f64,
+    failed: usize,
+    free_regions: usize,
+    fragmentation: f64, // 1 - largest free region / free bytes
+}
+
+fn run<H: Heap>(placement: Placement, seed: u64) -> Report {
+    let start = unsafe { alloc(Layout::from_size_align(HEAP_SIZE, 4096).unwrap()) } as usize;
+    let mut allocator = H::new(placement);
+    unsafe { allocator.init(start, HEAP_SIZE) };
+
+    let mut rng = Rng(seed);
+    let mut short_lived: Vec<(*mut u8, Layout)> = Vec::with_capacity(OPERATIONS);
+    let mut long_lived: Vec<(*mut u8, Layout)> = Vec::with_capacity(OPERATIONS);
+    let mut failed = 0;
+
+    let timer = Instant::now();
+    for _ in 0..OPERATIONS {
+        // Keeps the number of short lived allocations roughly between 1000 and 4000
+        let live = short_lived.len();
+        if live < 1000 || (live < 4000 && rng.below(2) == 0) {
+            let size = match rng.below(100) {
+                0..=69 => 16 + rng.below(240),
+                70..=94 => 256 + rng.below(3840),
+                _ => 4096 + rng.below(60 * 1024),
+            };
+            let layout = Layout::from_size_align(size, 8 << rng.below(4)).unwrap();
+            let ptr = unsafe { allocator.allocate(layout) };
+            if ptr.is_null() {
+                failed += 1;
+            } else if rng.below(100) < 3 {
+                long_lived.push((ptr, layout));
+            } else {
+                short_lived.push((ptr, layout));
+            }
+        } else {
+            let (ptr, layout) = short_lived.swap_remove(rng.below(short_lived.len()));
+            unsafe { allocator.deallocate(ptr, layout) };
+        }
+    }
+    let elapsed = timer.elapsed();
+
+    for (ptr, layout) in short_lived {
+        unsafe { allocator.deallocate(ptr, layout) };
+    }
+    let regions = allocator.free_regions();
+    let free: usize = regions.iter().sum();
+    let largest = regions.iter().copied().max().unwrap_or(0);
+
+    Report {
+        nanos_per_op: elapsed.as_nanos() as f64 / OPERATIONS as f64,
+        failed,
+        free_regions: regions.len(),
+        fragmentation: 1.0 - largest as f64 / free as f64,
+    }
+}
+
+fn compare<H: Heap>(name: &str) {
+    println!("{}", name);
+    println!("{:<10} {:>10} {:>8} {:>13} {:>14}", "placement", "ns/op", "failed", "free regions", "fragmentation");
+    for placement in [Placement::FirstFit, Placement::BestFit] {
+        let reports: Vec<Report> = (1..=3).map(|seed| run::<H>(placement, seed * 0x2545_f491_4f6c_dd1d)).collect();
+        let count = reports.len() as f64;
+        println!("{:<10} {:>10.1} {:>8} {:>13} {:>13.1}%",
+            format!("{:?}", placement),
+            reports.iter().map(|r| r.nanos_per_op).sum::<f64>() / count,
+            reports.iter().map(|r| r.failed).sum::<usize>(),
+            reports.iter().map(|r| r.free_regions).sum::<usize>() / reports.len(),
+            100.0 * reports.iter().map(|r| r.fragmentation).sum::<f64>() / count);
+    }
+}
+
+fn main() {
+    compare::<LinkedListAllocator>("linked list");
+    println!();
+    compare::<FixedSizeBlockAllocator>("fixed size blocks in front of the linked list");
+}
@@ -0,0 +1,280 @@
+use core::{mem, ptr};
+
+use crate::linked_list::{LinkedListAllocator, Placement};
+use core::alloc::Layout;
+
+// The size classes, every one is a power of two so blocks are naturally aligned to their size.
+// Anything bigger than the last class goes straight to the linked list allocator.
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+// Empty size classes are refilled with a whole slab at a time instead of one block,
+// which keeps small blocks of the same class together and away from the big ones.
+const SLAB_SIZE: usize = 4096;
+
+struct FreeBlock {
+    next: Option<&'static mut FreeBlock>,
+}
+
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut FreeBlock>; BLOCK_SIZES.len()],
+    fallback: LinkedListAllocator,
+}
+
+impl FixedSizeBlockAllocator {
+    pub const fn new() -> Self {
+        Self::with_placement(Placement::FirstFit)
+    }
+
+    // The placement policy is used by the linked list behind the size classes
+    pub const fn with_placement(placement: Placement) -> Self {
+        const EMPTY: Option<&'static mut FreeBlock> = None;
+        FixedSizeBlockAllocator {
+            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback: LinkedListAllocator::with_placement(placement),
+        }
+    }
+
+    /// # Safety
+    /// Same as LinkedListAllocator::init.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        unsafe {
+            self.fallback.init(heap_start, heap_size);
+        }
+    }
+
+    pub fn heap_end(&self) -> usize {
+        self.fallback.heap_end()
+    }
+
+    pub fn heap_size(&self) -> usize {
+        self.fallback.heap_size()
+    }
+
+    /// # Safety
+    /// Same as LinkedListAllocator::extend.
+    pub unsafe fn extend(&mut self, size: usize) {
+        unsafe {
+            self.fallback.extend(size);
+        }
+    }
+
+    // Blocks sitting in the size class lists are never handed back to the linked list,
+    // so only memory the fallback sees as free can be released.
+    pub fn shrink(&mut self, page_size: usize, min_size: usize) -> Option<(usize, usize)> {
+        self.fallback.shrink(page_size, min_size)
+    }
+
+    pub fn free_regions(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.fallback.free_regions()
+    }
+
+    // Bytes held in the size class lists, free but only usable for blocks of their class
+    pub fn cached_bytes(&self) -> usize {
+        let mut total = 0;
+        for (index, head) in self.list_heads.iter().enumerate() {
+            let mut current = head.as_deref();
+            while let Some(block) = current {
+                total += BLOCK_SIZES[index];
+                current = block.next.as_deref();
+            }
+        }
+        total
+    }
+
+    /// # Safety
+    /// The allocator must have been initialized.
+    pub unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match list_index(&layout) {
+            Some(index) => {
+                if self.list_heads[index].is_none() {
+                    unsafe { self.refill(index) };
+                }
+                match self.list_heads[index].take() {
+                    Some(block) => {
+                        self.list_heads[index] = block.next.take();
+                        block as *mut FreeBlock as *mut u8
+                    }
+                    None => ptr::null_mut(),
+                }
+            }
+            None => unsafe { self.fallback.allocate(layout) },
+        }
+    }
+
+    /// # Safety
+    /// ptr must have been returned by allocate on this allocator with the same layout
+    /// and must not be used afterwards.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match list_index(&layout) {
+            Some(index) => unsafe { self.push_block(index, ptr) },
+            None => unsafe { self.fallback.deallocate(ptr, layout) },
+        }
+    }
+
+    /// Succeeds within a size class, or for big blocks when the linked list can resize them.
+    ///
+    /// # Safety
+    /// Same as LinkedListAllocator::resize_in_place.
+    pub unsafe fn resize_in_place(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+        let Ok(new_layout) = Layout::from_size_align(new_size, layout.align()) else {
+            return false;
+        };
+        match (list_index(&layout), list_index(&new_layout)) {
+            (Some(old), Some(new)) => old == new,
+            (None, None) => unsafe { self.fallback.resize_in_place(ptr, layout, new_size) },
+            _ => false,
+        }
+    }
+
+    pub fn reaches_heap_end(&self, ptr: *mut u8, layout: Layout) -> bool {
+        list_index(&layout).is_none() && self.fallback.reaches_heap_end(ptr, layout)
+    }
+
+    // Carves a fresh slab from the fallback allocator into blocks of the given class.
+    // Leaves the list empty if the fallback is out of memory.
+    unsafe fn refill(&mut self, index: usize) {
+        let block_size = BLOCK_SIZES[index];
+        let slab_layout = Layout::from_size_align(SLAB_SIZE, block_size).unwrap();
+        let slab = unsafe { self.fallback.allocate(slab_layout) };
+        if slab.is_null() {
+            return;
+        }
+
+        // Push in reverse so the blocks are handed out in address order
+        for offset in (0..SLAB_SIZE).step_by(block_size).rev() {
+            unsafe { self.push_block(index, slab.add(offset)) };
+        }
+    }
+
+    unsafe fn push_block(&mut self, index: usize, ptr: *mut u8) {
+        // Every block can hold a FreeBlock since the smallest class is the size of one
+        assert!(mem::size_of::<FreeBlock>() <= BLOCK_SIZES[index]);
+        assert!(mem::align_of::<FreeBlock>() <= BLOCK_SIZES[index]);
+        let block = FreeBlock {
+            next: self.list_heads[index].take(),
+        };
+        let block_ptr = ptr as *mut FreeBlock;
+        unsafe {
+            block_ptr.write(block);
+            self.list_heads[index] = Some(&mut *block_ptr);
+        }
+    }
+}
+
+impl Default for FixedSizeBlockAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Picks the smallest size class that fits the layout, or None if it needs the fallback
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{arena, Rng};
+    use std::vec::Vec;
+
+    fn heap(size: usize) -> FixedSizeBlockAllocator {
+        let mut allocator = FixedSizeBlockAllocator::new();
+        unsafe { allocator.init(arena(size), size) };
+        allocator
+    }
+
+    #[test]
+    fn list_index_picks_smallest_fitting_class() {
+        let index = |size, align| list_index(&Layout::from_size_align(size, align).unwrap());
+        assert_eq!(index(0, 1), Some(0));
+        assert_eq!(index(8, 8), Some(0));
+        assert_eq!(index(9, 1), Some(1));
+        assert_eq!(index(3, 64), Some(3));
+        assert_eq!(index(2048, 8), Some(BLOCK_SIZES.len() - 1));
+        assert_eq!(index(2049, 8), None);
+        assert_eq!(index(8, 4096), None);
+    }
+
+    #[test]
+    fn refill_carves_a_whole_slab() {
+        let mut allocator = heap(64 * 1024);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let first = unsafe { allocator.allocate(layout) };
+        assert_eq!(allocator.cached_bytes(), SLAB_SIZE - 64);
+
+        // Blocks come out of the slab in address order
+        let second = unsafe { allocator.allocate(layout) };
+        assert_eq!(second as usize, first as usize + 64);
+
+        unsafe {
+            allocator.deallocate(second, layout);
+            allocator.deallocate(first, layout);
+        }
+        assert_eq!(allocator.cached_bytes(), SLAB_SIZE);
+        assert_eq!(unsafe { allocator.allocate(layout) }, first);
+    }
+
+    #[test]
+    fn large_blocks_use_the_fallback() {
+        let mut allocator = heap(64 * 1024);
+        let free_before: usize = allocator.free_regions().map(|(_, size)| size).sum();
+        let layout = Layout::from_size_align(8192, 8).unwrap();
+        let block = unsafe { allocator.allocate(layout) };
+        assert!(!block.is_null());
+        assert_eq!(allocator.cached_bytes(), 0);
+
+        unsafe { allocator.deallocate(block, layout) };
+        let free_after: usize = allocator.free_regions().map(|(_, size)| size).sum();
+        assert_eq!(free_after, free_before);
+    }
+
+    #[test]
+    fn resize_in_place_stays_within_class_or_fallback() {
+        let mut allocator = heap(64 * 1024);
+        let small = Layout::from_size_align(40, 8).unwrap();
+        let block = unsafe { allocator.allocate(small) };
+        assert!(unsafe { allocator.resize_in_place(block, small, 64) });
+        assert!(!unsafe { allocator.resize_in_place(block, small, 65) });
+        assert!(!unsafe { allocator.resize_in_place(block, small, 4096) });
+
+        let big = Layout::from_size_align(4096, 8).unwrap();
+        let block = unsafe { allocator.allocate(big) };
+        assert!(unsafe { allocator.resize_in_place(block, big, 8192) });
+        assert!(!unsafe { allocator.resize_in_place(block, Layout::from_size_align(8192, 8).unwrap(), 1024) });
+    }
+
+    // Random allocations and frees, every live block is filled with a tag that must survive
+    #[test]
+    fn random_alloc_free_keeps_blocks_disjoint() {
+        for seed in 1..=8u64 {
+            let mut allocator = heap(512 * 1024);
+            let mut rng = Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+            let mut live: Vec<(*mut u8, Layout, u8)> = Vec::new();
+
+            for step in 0..4000 {
+                if live.is_empty() || rng.below(100) < 55 {
+                    let max_size = if rng.below(20) == 0 { 8192 } else { 512 };
+                    let size = 1 + rng.below(max_size);
+                    let align = 1 << rng.below(7);
+                    let layout = Layout::from_size_align(size, align).unwrap();
+                    let ptr = unsafe { allocator.allocate(layout) };
+                    if ptr.is_null() {
+                        continue;
+                    }
+                    assert_eq!(ptr as usize % align, 0);
+
+                    let tag = step as u8;
+                    unsafe { core::ptr::write_bytes(ptr, tag, size) };
+                    live.push((ptr, layout, tag));
+                } else {
+                    let (ptr, layout, tag) = live.swap_remove(rng.below(live.len()));
+                    let intact = (0..layout.size()).all(|i| unsafe { *ptr.add(i) } == tag);
+                    assert!(intact, "seed {}: block {:p} was overwritten", seed, ptr);
+                    unsafe { allocator.deallocate(ptr, layout) };
+                }
+            }
+        }
+    }
+}
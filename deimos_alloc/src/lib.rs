@@ -0,0 +1,42 @@
+#![no_std]
+
+#[cfg(test)]
+extern crate std;
+
+pub mod linked_list;
+pub mod fixed_size_block;
+
+// Rounds addr up to the next multiple of align, which has to be a power of two
+pub(crate) fn align_up(addr: usize, align: usize) -> usize {
+    assert!(align.is_power_of_two(), "`align` must be a power of two");
+    (addr + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+pub(crate) mod test_util {
+    use std::alloc::{alloc, Layout};
+
+    // Page aligned memory for a test heap. It is leaked, the allocators hold 'static references.
+    pub fn arena(size: usize) -> usize {
+        let layout = Layout::from_size_align(size, 4096).unwrap();
+        let ptr = unsafe { alloc(layout) };
+        assert!(!ptr.is_null());
+        ptr as usize
+    }
+
+    // xorshift64, deterministic so a failing seed can be replayed
+    pub struct Rng(pub u64);
+
+    impl Rng {
+        pub fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        pub fn below(&mut self, bound: usize) -> usize {
+            (self.next() % bound as u64) as usize
+        }
+    }
+}
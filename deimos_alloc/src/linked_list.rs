@@ -0,0 +1,695 @@
+use core::{ptr, mem};
+
+use core::alloc::Layout;
+use crate::align_up;
+
+
+/// Which free region an allocation is taken from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// The first region from the start of the heap that fits. Fast, but piles
+    /// small leftovers up at the front of the list.
+    FirstFit,
+    /// The region that leaves the smallest leftover. Always walks the whole list,
+    /// but keeps big regions intact for longer.
+    BestFit,
+}
+
+pub struct LinkedListAllocator {
+    head: ListNode,
+    heap_start: usize,
+    heap_size: usize,
+    placement: Placement,
+}
+
+impl LinkedListAllocator {
+    pub const fn new() -> Self {
+        Self::with_placement(Placement::FirstFit)
+    }
+
+    pub const fn with_placement(placement: Placement) -> Self {
+        Self {
+            head: ListNode::new(0),
+            heap_start: 0,
+            heap_size: 0,
+            placement,
+        }
+    }
+
+    pub fn placement(&self) -> Placement {
+        self.placement
+    }
+
+    /// # Safety
+    /// The memory from heap_start to heap_start + heap_size must be valid, unused and not
+    /// accessed by anything else for as long as the allocator lives. Call only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = heap_start;
+        self.heap_size = heap_size;
+        unsafe {
+            self.add_free_region(heap_start, heap_size)
+        }
+    }
+
+    pub fn heap_end(&self) -> usize {
+        self.heap_start + self.heap_size
+    }
+
+    pub fn heap_size(&self) -> usize {
+        self.heap_size
+    }
+
+    /// Appends `size` bytes of fresh memory directly after the current end of the heap.
+    ///
+    /// # Safety
+    /// The memory must be valid and unused, just like the memory passed to init.
+    pub unsafe fn extend(&mut self, size: usize) {
+        let old_end = self.heap_end();
+        self.heap_size += size;
+        unsafe {
+            self.add_free_region(old_end, size);
+        }
+    }
+
+    // Removes the page aligned part of a free region touching the end of the heap and
+    // returns it as (start, size), so the caller can unmap it. Keeps at least `min_size` bytes.
+    pub fn shrink(&mut self, page_size: usize, min_size: usize) -> Option<(usize, usize)> {
+        let heap_end = self.heap_end();
+        let min_end = self.heap_start + min_size;
+
+        let mut current = &mut self.head;
+        while current.next.as_ref().is_some_and(|next| next.end_addr() != heap_end) {
+            current = current.next.as_mut().unwrap();
+        }
+        let last = current.next.as_mut()?;
+
+        let cut = align_up(last.start_addr(), page_size).max(align_up(min_end, page_size));
+        if cut >= heap_end {
+            return None;
+        }
+
+        let remaining = cut - last.start_addr();
+        if remaining == 0 {
+            current.next = last.next.take();
+        } else if remaining >= mem::size_of::<ListNode>() {
+            last.size = remaining;
+        } else {
+            return None; // the leftover could not hold a ListNode
+        }
+
+        self.heap_size -= heap_end - cut;
+        Some((cut, heap_end - cut))
+    }
+
+    // Iterates over the free regions as (start address, size), sorted by address
+    pub fn free_regions(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let mut current = self.head.next.as_deref();
+        core::iter::from_fn(move || {
+            let node = current?;
+            current = node.next.as_deref();
+            Some((node.start_addr(), node.size))
+        })
+    }
+
+    /// Returns null when no free region fits the layout.
+    ///
+    /// # Safety
+    /// The allocator must have been initialized.
+    pub unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = LinkedListAllocator::size_align(layout);
+
+        if let Some((region, alloc_start)) = self.find_region(size, align) {
+            let region_start = region.start_addr();
+            let alloc_end = alloc_start.checked_add(size).expect("[Err: Overflow]");
+            let excess_size = region.end_addr() - alloc_end;
+            if excess_size > 0 {
+                unsafe {
+                    self.add_free_region(alloc_end, excess_size);
+                }
+            }
+            // Alignment padding in front of the allocation goes back to the list too
+            if alloc_start > region_start {
+                unsafe {
+                    self.add_free_region(region_start, alloc_start - region_start);
+                }
+            }
+            alloc_start as *mut u8
+        }
+        else {
+            ptr::null_mut()
+        }
+    }
+
+    /// # Safety
+    /// ptr must have been returned by allocate on this allocator with the same layout
+    /// and must not be used afterwards.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = LinkedListAllocator::size_align(layout);
+        unsafe { self.add_free_region(ptr as usize, size); }
+    }
+
+    /// Grows or shrinks the allocation at ptr without moving it. Growing takes memory from
+    /// a free region directly after the block, shrinking gives the tail back to the list.
+    /// Returns false if that is not possible, the allocation is unchanged then.
+    ///
+    /// # Safety
+    /// ptr must have been returned by allocate on this allocator with the given layout.
+    /// On success it has to be freed with the new size from then on.
+    pub unsafe fn resize_in_place(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+        let (old_size, align) = LinkedListAllocator::size_align(layout);
+        let Ok(new_layout) = Layout::from_size_align(new_size, align) else {
+            return false;
+        };
+        let (new_size, _) = LinkedListAllocator::size_align(new_layout);
+        let block_start = ptr as usize;
+
+        if new_size <= old_size {
+            let tail = old_size - new_size;
+            if tail == 0 {
+                return true;
+            }
+            if tail < mem::size_of::<ListNode>() {
+                return false; // the tail could not be tracked
+            }
+            unsafe { self.add_free_region(block_start + new_size, tail) };
+            return true;
+        }
+
+        let needed = new_size - old_size;
+        let next_start = block_start + old_size;
+        let mut current = &mut self.head;
+        while current.next.as_ref().is_some_and(|next| next.start_addr() < next_start) {
+            current = current.next.as_mut().unwrap();
+        }
+        let Some(next) = current.next.as_mut() else {
+            return false;
+        };
+        if next.start_addr() != next_start || next.size < needed {
+            return false;
+        }
+
+        let remaining = next.size - needed;
+        if remaining == 0 {
+            current.next = next.next.take();
+        } else if remaining >= mem::size_of::<ListNode>() {
+            // Move the node up to the new start of the free region
+            let mut moved = ListNode::new(remaining);
+            moved.next = next.next.take();
+            let moved_ptr = (next_start + needed) as *mut ListNode;
+            unsafe {
+                moved_ptr.write(moved);
+                current.next = Some(&mut *moved_ptr);
+            }
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// Whether the allocation is the last thing before the end of the heap, apart from
+    /// free memory. Extending the heap lets such a block grow in place.
+    pub fn reaches_heap_end(&self, ptr: *mut u8, layout: Layout) -> bool {
+        let (size, _) = LinkedListAllocator::size_align(layout);
+        let block_end = ptr as usize + size;
+        block_end == self.heap_end()
+            || self.free_regions().any(|(start, size)| start == block_end && start + size == self.heap_end())
+    }
+
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+
+        // Assertions to verify at least one ListNode can fit in the free region
+        assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
+        assert!(size >= mem::size_of::<ListNode>());
+
+        let head_addr = self.head.start_addr();
+        let mut current = &mut self.head;
+        while let Some(ref mut next_node) = current.next {
+            if next_node.start_addr() > addr {
+                break; // The right place to insert free node, ensures that the free regions are
+                       // all sorted by address
+            }
+            current = current.next.as_mut().unwrap();
+        }
+
+
+
+        let mut node = ListNode::new(size);
+        node.next = current.next.take();
+        let node_ptr = addr as *mut ListNode;
+
+        //safety guarentees are above with assertions
+        unsafe {
+            node_ptr.write(node);
+            current.next = Some(&mut *node_ptr);
+        }
+        let current_start_addr = current.start_addr();
+        let current_end_addr = current.end_addr();
+
+
+        let new_ref = current.next.as_mut().unwrap();
+        let new_ref_start_addr = new_ref.start_addr();
+        let new_ref_end_addr = new_ref.end_addr();
+        
+
+        let next_node_data = if let Some(ref mut next_node) = new_ref.next {
+            if new_ref_end_addr == next_node.start_addr() {
+                // The blocks are touching!
+                (Some(next_node.size), Some(next_node.next.take()))
+            }
+            else {(None, None)}
+        }
+        else {(None, None)};
+
+        if let (Some(next_node_size), Some(next_node_next)) = next_node_data {
+                new_ref.size += next_node_size;
+                new_ref.next = next_node_next;
+        }
+
+        if current_start_addr != head_addr && current_end_addr == new_ref_start_addr {
+            // The previous block and new block are touching!
+            current.size += new_ref.size;
+            current.next = new_ref.next.take();
+        }
+    }
+    
+    // Looks for a free region with the given size and alignment and removes it from the list 
+    // Returns a tuple containing the ListNode and start address of the allocation
+    fn find_region(&mut self, size: usize, align: usize) 
+        -> Option<(&'static mut ListNode, usize)> 
+    {
+        match self.placement {
+            Placement::FirstFit => self.take_first_fit(size, align, 0),
+            Placement::BestFit => {
+                let best = self.best_fit(size, align)?;
+                self.take_first_fit(size, align, best)
+            }
+        }
+    }
+
+    // Removes the first region starting at or after `from` that fits the allocation
+    fn take_first_fit(&mut self, size: usize, align: usize, from: usize)
+        -> Option<(&'static mut ListNode, usize)>
+    {
+        let mut current = &mut self.head.next;
+
+        loop {
+            let alloc_start = if let Some(region) = current.as_deref() {
+                if region.start_addr() >= from {
+                    Self::alloc_from_region(region, size, align).ok()
+                } else {
+                    None
+                }
+            } else {
+                return None; 
+            };
+
+            if let Some(start) = alloc_start {
+                let node = current.take().unwrap();
+                *current = node.next.take();
+                return Some((node, start));
+            }
+
+            current = &mut current.as_mut().unwrap().next;
+        }
+    }
+
+    // Returns the start address of the region that fits with the smallest leftover
+    fn best_fit(&self, size: usize, align: usize) -> Option<usize> {
+        let mut best: Option<(usize, usize)> = None; // (region start, leftover)
+        let mut current = self.head.next.as_deref();
+        while let Some(region) = current {
+            if let Ok(alloc_start) = Self::alloc_from_region(region, size, align) {
+                let leftover = region.end_addr() - (alloc_start + size);
+                if best.is_none_or(|(_, best_leftover)| leftover < best_leftover) {
+                    best = Some((region.start_addr(), leftover));
+                    if leftover == 0 {
+                        break; // cannot do better than an exact fit
+                    }
+                }
+            }
+            current = region.next.as_deref();
+        }
+        best.map(|(start, _)| start)
+    }
+
+    fn alloc_from_region(region: &ListNode, size: usize, align: usize) 
+        -> Result<usize, ()> {
+        let mut alloc_start = align_up(region.start_addr(), align);
+        if alloc_start != region.start_addr()
+            && alloc_start - region.start_addr() < mem::size_of::<ListNode>() {
+            // The padding in front could not hold a ListNode, skip to the next aligned address
+            alloc_start = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+        }
+        let alloc_end: usize = alloc_start.checked_add(size).ok_or(())?;
+        if alloc_end > region.end_addr() {
+            return Err(()); // Region too small >:(
+        }
+
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+            return Err(());
+        }
+
+        Ok(alloc_start)
+    }
+
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(mem::align_of::<ListNode>())
+            .expect("adjusting alignment failed")
+            .pad_to_align();
+        let size = layout.size().max(mem::size_of::<ListNode>());
+        (size, layout.align())
+    }
+}
+
+impl Default for LinkedListAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct ListNode {
+    size: usize,
+    next: Option<&'static mut ListNode>
+}
+
+impl ListNode {
+    const fn new(size: usize) -> Self {
+        ListNode { size, next: None }
+    }
+
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{arena, Rng};
+    use std::vec::Vec;
+
+    const NODE: usize = mem::size_of::<ListNode>();
+
+    const PLACEMENTS: [Placement; 2] = [Placement::FirstFit, Placement::BestFit];
+
+    fn heap(size: usize) -> (LinkedListAllocator, usize) {
+        heap_with(Placement::FirstFit, size)
+    }
+
+    fn heap_with(placement: Placement, size: usize) -> (LinkedListAllocator, usize) {
+        let start = arena(size);
+        let mut allocator = LinkedListAllocator::with_placement(placement);
+        unsafe { allocator.init(start, size) };
+        (allocator, start)
+    }
+
+    // Free regions of 256, 64 and 128 bytes (in that order), kept apart by 16 byte blocks
+    fn heap_with_holes(placement: Placement) -> (LinkedListAllocator, [usize; 3]) {
+        let (mut allocator, _) = heap_with(placement, 512);
+        let separator = Layout::from_size_align(16, 8).unwrap();
+        let layouts = [256, 64, 128].map(|size| Layout::from_size_align(size, 8).unwrap());
+        let holes = layouts.map(|layout| {
+            let hole = unsafe { allocator.allocate(layout) } as usize;
+            unsafe { allocator.allocate(separator) };
+            hole
+        });
+        for (hole, layout) in holes.iter().zip(layouts) {
+            unsafe { allocator.deallocate(*hole as *mut u8, layout) };
+        }
+        (allocator, holes)
+    }
+
+    fn regions(allocator: &LinkedListAllocator) -> Vec<(usize, usize)> {
+        allocator.free_regions().collect()
+    }
+
+    // The free list is sorted, inside the heap and fully coalesced
+    fn check_free_list(allocator: &LinkedListAllocator) {
+        let mut previous_end = None;
+        for (start, size) in allocator.free_regions() {
+            assert!(size >= NODE);
+            assert!(start >= allocator.heap_start && start + size <= allocator.heap_end());
+            if let Some(end) = previous_end {
+                assert!(start > end, "free regions {:#x} and {:#x} overlap or touch", end, start);
+            }
+            previous_end = Some(start + size);
+        }
+    }
+
+    #[test]
+    fn size_align_rounds_up_to_list_nodes() {
+        let (size, align) = LinkedListAllocator::size_align(Layout::from_size_align(1, 1).unwrap());
+        assert_eq!((size, align), (NODE, mem::align_of::<ListNode>()));
+
+        let (size, align) = LinkedListAllocator::size_align(Layout::from_size_align(17, 1).unwrap());
+        assert_eq!((size, align), (24, 8));
+
+        let (size, align) = LinkedListAllocator::size_align(Layout::from_size_align(100, 64).unwrap());
+        assert_eq!((size, align), (128, 64));
+
+        let (size, _) = LinkedListAllocator::size_align(Layout::from_size_align(0, 1).unwrap());
+        assert_eq!(size, NODE);
+    }
+
+    #[test]
+    fn alloc_from_region_respects_bounds_and_alignment() {
+        let start = arena(4096);
+        let region = unsafe {
+            let node = start as *mut ListNode;
+            node.write(ListNode::new(256));
+            &*node
+        };
+
+        assert_eq!(LinkedListAllocator::alloc_from_region(region, 256, 8), Ok(start));
+        assert_eq!(LinkedListAllocator::alloc_from_region(region, 257, 8), Err(()));
+        // An excess too small for a ListNode would be lost
+        assert_eq!(LinkedListAllocator::alloc_from_region(region, 256 - 8, 8), Err(()));
+        assert_eq!(LinkedListAllocator::alloc_from_region(region, 256 - NODE, 8), Ok(start));
+        assert_eq!(LinkedListAllocator::alloc_from_region(region, 64, 128), Ok(start));
+    }
+
+    #[test]
+    fn alloc_from_region_leaves_room_for_front_padding() {
+        let start = arena(4096);
+        let region = unsafe {
+            let node = (start + 8) as *mut ListNode;
+            node.write(ListNode::new(512));
+            &*node
+        };
+
+        // 8 bytes of padding cannot hold a ListNode, so the allocation moves up
+        let alloc_start = LinkedListAllocator::alloc_from_region(region, 32, 16).unwrap();
+        assert_eq!(alloc_start, start + 32);
+        assert_eq!(LinkedListAllocator::alloc_from_region(region, 64, 256), Ok(start + 256));
+    }
+
+    #[test]
+    fn freeing_in_any_order_coalesces() {
+        let orders: [[usize; 3]; 6] = [[0, 1, 2], [0, 2, 1], [1, 0, 2], [1, 2, 0], [2, 0, 1], [2, 1, 0]];
+        for order in orders {
+            let (mut allocator, start) = heap(3 * 64);
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            let blocks: Vec<*mut u8> = (0..3).map(|_| unsafe { allocator.allocate(layout) }).collect();
+            assert!(blocks.iter().all(|b| !b.is_null()));
+            assert!(regions(&allocator).is_empty());
+
+            for index in order {
+                unsafe { allocator.deallocate(blocks[index], layout) };
+                check_free_list(&allocator);
+            }
+            assert_eq!(regions(&allocator), [(start, 3 * 64)], "order {:?}", order);
+        }
+    }
+
+    #[test]
+    fn region_at_heap_start_merges_with_freed_neighbour() {
+        let (mut allocator, start) = heap(4096);
+        let layout = Layout::from_size_align(128, 8).unwrap();
+        let first = unsafe { allocator.allocate(layout) };
+        let second = unsafe { allocator.allocate(layout) };
+        assert_eq!(first as usize, start);
+
+        unsafe { allocator.deallocate(first, layout) };
+        unsafe { allocator.deallocate(second, layout) };
+        assert_eq!(regions(&allocator), [(start, 4096)]);
+    }
+
+    #[test]
+    fn aligned_allocation_returns_front_padding() {
+        let (mut allocator, start) = heap(8192);
+        let small = unsafe { allocator.allocate(Layout::from_size_align(16, 8).unwrap()) };
+        let aligned = unsafe { allocator.allocate(Layout::from_size_align(64, 4096).unwrap()) };
+        assert_eq!(small as usize, start);
+        assert_eq!(aligned as usize, start + 4096);
+
+        // Everything between the two allocations is still free
+        assert_eq!(regions(&allocator)[0], (start + 16, 4096 - 16));
+    }
+
+    #[test]
+    fn exhaustion_returns_null() {
+        let (mut allocator, _) = heap(1024);
+        let too_big = unsafe { allocator.allocate(Layout::from_size_align(2048, 8).unwrap()) };
+        assert!(too_big.is_null());
+        let all = unsafe { allocator.allocate(Layout::from_size_align(1024, 8).unwrap()) };
+        assert!(!all.is_null());
+        let more = unsafe { allocator.allocate(Layout::from_size_align(8, 8).unwrap()) };
+        assert!(more.is_null());
+    }
+
+    #[test]
+    fn extend_and_shrink() {
+        let size = 4 * 4096;
+        let start = arena(2 * size);
+        let mut allocator = LinkedListAllocator::new();
+        unsafe { allocator.init(start, size) };
+
+        unsafe { allocator.extend(size) };
+        assert_eq!(allocator.heap_size(), 2 * size);
+        assert_eq!(regions(&allocator), [(start, 2 * size)]);
+
+        let block = unsafe { allocator.allocate(Layout::from_size_align(100, 8).unwrap()) };
+        assert_eq!(allocator.shrink(4096, size), Some((start + size, size)));
+        assert_eq!(allocator.heap_size(), size);
+        assert_eq!(allocator.shrink(4096, size), None);
+        check_free_list(&allocator);
+
+        unsafe { allocator.deallocate(block, Layout::from_size_align(100, 8).unwrap()) };
+        assert_eq!(regions(&allocator), [(start, size)]);
+    }
+
+    #[test]
+    fn resize_in_place_grows_into_next_free_region() {
+        let (mut allocator, start) = heap(4096);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let block = unsafe { allocator.allocate(layout) };
+
+        assert!(unsafe { allocator.resize_in_place(block, layout, 1024) });
+        assert_eq!(regions(&allocator), [(start + 1024, 4096 - 1024)]);
+
+        // Taking the whole free region removes its node
+        let layout = Layout::from_size_align(1024, 8).unwrap();
+        assert!(unsafe { allocator.resize_in_place(block, layout, 4096) });
+        assert!(regions(&allocator).is_empty());
+        assert!(!unsafe { allocator.resize_in_place(block, Layout::from_size_align(4096, 8).unwrap(), 4104) });
+    }
+
+    #[test]
+    fn resize_in_place_fails_when_blocked() {
+        let (mut allocator, _) = heap(4096);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let block = unsafe { allocator.allocate(layout) };
+        let neighbour = unsafe { allocator.allocate(layout) };
+        let free_before = regions(&allocator);
+
+        assert!(!unsafe { allocator.resize_in_place(block, layout, 128) });
+        // A leftover too small for a ListNode cannot be left behind either
+        assert!(!unsafe { allocator.resize_in_place(neighbour, layout, 4096 - 64 - 8) });
+        assert_eq!(regions(&allocator), free_before);
+    }
+
+    #[test]
+    fn resize_in_place_shrinks_and_coalesces() {
+        let (mut allocator, start) = heap(4096);
+        let layout = Layout::from_size_align(2048, 8).unwrap();
+        let block = unsafe { allocator.allocate(layout) };
+
+        assert!(unsafe { allocator.resize_in_place(block, layout, 512) });
+        assert_eq!(regions(&allocator), [(start + 512, 4096 - 512)]);
+        assert!(!unsafe { allocator.resize_in_place(block, Layout::from_size_align(512, 8).unwrap(), 504) });
+
+        unsafe { allocator.deallocate(block, Layout::from_size_align(512, 8).unwrap()) };
+        assert_eq!(regions(&allocator), [(start, 4096)]);
+    }
+
+    #[test]
+    fn reaches_heap_end_sees_through_trailing_free_memory() {
+        let (mut allocator, _) = heap(4096);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let first = unsafe { allocator.allocate(layout) };
+        let second = unsafe { allocator.allocate(layout) };
+        assert!(!allocator.reaches_heap_end(first, layout));
+        assert!(allocator.reaches_heap_end(second, layout));
+
+        let rest = Layout::from_size_align(4096 - 128, 8).unwrap();
+        let last = unsafe { allocator.allocate(rest) };
+        assert!(allocator.reaches_heap_end(last, rest));
+        assert!(!allocator.reaches_heap_end(second, layout));
+    }
+
+    #[test]
+    fn placement_picks_the_expected_hole() {
+        let layout = Layout::from_size_align(64, 8).unwrap();
+
+        let (mut first_fit, holes) = heap_with_holes(Placement::FirstFit);
+        assert_eq!(unsafe { first_fit.allocate(layout) } as usize, holes[0]);
+        assert_eq!(unsafe { first_fit.allocate(layout) } as usize, holes[0] + 64);
+
+        let (mut best_fit, holes) = heap_with_holes(Placement::BestFit);
+        assert_eq!(unsafe { best_fit.allocate(layout) } as usize, holes[1]);
+        assert_eq!(unsafe { best_fit.allocate(layout) } as usize, holes[2]);
+    }
+
+    // Random allocations and frees checked against a model of the live allocations
+    #[test]
+    fn random_alloc_free_matches_model() {
+        const HEAP: usize = 256 * 1024;
+        for (seed, placement) in (1..=9u64).zip(PLACEMENTS.iter().cycle()) {
+            let (mut allocator, start) = heap_with(*placement, HEAP);
+            let mut rng = Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+            let mut live: Vec<(usize, Layout)> = Vec::new();
+
+            for _ in 0..4000 {
+                if !live.is_empty() && rng.below(100) < 15 {
+                    let index = rng.below(live.len());
+                    let (ptr, layout) = live[index];
+                    let new_size = 1 + rng.below(2 * layout.size());
+                    if unsafe { allocator.resize_in_place(ptr as *mut u8, layout, new_size) } {
+                        live[index].1 = Layout::from_size_align(new_size, layout.align()).unwrap();
+                    }
+                } else if live.is_empty() || rng.below(100) < 55 {
+                    let max_size = if rng.below(10) == 0 { 16 * 1024 } else { 256 };
+                    let size = 1 + rng.below(max_size);
+                    let align = 1 << rng.below(13);
+                    let layout = Layout::from_size_align(size, align).unwrap();
+                    let ptr = unsafe { allocator.allocate(layout) } as usize;
+                    if ptr == 0 {
+                        continue;
+                    }
+
+                    let (padded, _) = LinkedListAllocator::size_align(layout);
+                    assert_eq!(ptr % align, 0);
+                    assert!(ptr >= start && ptr + padded <= start + HEAP);
+                    for &(other, other_layout) in &live {
+                        let (other_size, _) = LinkedListAllocator::size_align(other_layout);
+                        assert!(ptr + padded <= other || other + other_size <= ptr,
+                            "seed {}: {:#x} overlaps live {:#x}", seed, ptr, other);
+                    }
+                    live.push((ptr, layout));
+                } else {
+                    let (ptr, layout) = live.swap_remove(rng.below(live.len()));
+                    unsafe { allocator.deallocate(ptr as *mut u8, layout) };
+                }
+
+                check_free_list(&allocator);
+                let free: usize = allocator.free_regions().map(|(_, size)| size).sum();
+                let used: usize = live.iter()
+                    .map(|&(_, layout)| LinkedListAllocator::size_align(layout).0)
+                    .sum();
+                assert_eq!(free + used, HEAP, "seed {}: memory leaked", seed);
+            }
+
+            for (ptr, layout) in live.drain(..) {
+                unsafe { allocator.deallocate(ptr as *mut u8, layout) };
+            }
+            assert_eq!(regions(&allocator), [(start, HEAP)], "seed {}", seed);
+        }
+    }
+}
@@ -0,0 +1,246 @@
+// Embeds the function symbols of the kernel ELF into its .ksymtab section.
+//
+//   ksyms <kernel elf>
+//
+// The kernel reserves .ksymtab (see deimos/src/debugging/symbols.rs) and the table is patched
+// into the file in place, so this runs after linking and before the boot image is made.
+// The format, all little endian:
+//
+//   header:  magic "KSYMTAB1", entry count u32, offset of the strings u32
+//   entries: address u64, size u32, name offset into the strings u32, sorted by address
+//   strings: the demangled names, each followed by a 0 byte
+
+use std::{env, fs, process};
+
+const MAGIC: &[u8; 8] = b"KSYMTAB1";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_NOBITS: u32 = 8;
+const STT_FUNC: u8 = 2;
+const SYMBOL_SIZE: usize = 24; // Elf64_Sym
+
+struct Section {
+    name: String,
+    kind: u32,
+    offset: usize,
+    size: usize,
+    link: u32,
+}
+
+#[derive(Debug, PartialEq)]
+struct Symbol {
+    address: u64,
+    size: u64,
+    name: String,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let [_, path] = args.as_slice() else {
+        eprintln!("usage: ksyms <kernel elf>");
+        process::exit(2);
+    };
+    if let Err(err) = run(path) {
+        eprintln!("ksyms: {}", err);
+        process::exit(1);
+    }
+}
+
+fn run(path: &str) -> Result<(), String> {
+    let mut elf = fs::read(path).map_err(|err| format!("could not read {}: {}", path, err))?;
+    let sections = sections(&elf)?;
+    let symbols = function_symbols(&elf, &sections)?;
+    let table = encode(&symbols);
+
+    let ksymtab = sections.iter()
+        .find(|section| section.name == ".ksymtab")
+        .ok_or("no .ksymtab section, is this the kernel?")?;
+    patch(&mut elf, ksymtab, &table)?;
+
+    fs::write(path, &elf).map_err(|err| format!("could not write {}: {}", path, err))?;
+    println!("ksyms: embedded {} symbols ({} of {} bytes)", symbols.len(), table.len(), ksymtab.size);
+    Ok(())
+}
+
+// Writes the table over the reserved section, which has to hold all of it
+fn patch(elf: &mut [u8], ksymtab: &Section, table: &[u8]) -> Result<(), String> {
+    if ksymtab.kind == SHT_NOBITS {
+        return Err(".ksymtab takes no space in the file".into());
+    }
+    if table.len() > ksymtab.size {
+        return Err(format!("the table needs {} bytes but .ksymtab only has {}, raise KSYMTAB_SIZE \
+            in deimos/src/debugging/symbols.rs", table.len(), ksymtab.size));
+    }
+    let target = elf.get_mut(ksymtab.offset..ksymtab.offset + ksymtab.size)
+        .ok_or(".ksymtab lies outside of the file")?;
+    if !target.starts_with(MAGIC) {
+        return Err(".ksymtab does not start with the table magic".into());
+    }
+    target.fill(0);
+    target[..table.len()].copy_from_slice(table);
+    Ok(())
+}
+
+fn sections(elf: &[u8]) -> Result<Vec<Section>, String> {
+    if !elf.starts_with(b"\x7fELF") || elf.get(4) != Some(&2) || elf.get(5) != Some(&1) {
+        return Err("not a 64 bit little endian ELF file".into());
+    }
+    let header_offset = read_u64(elf, 0x28)? as usize;
+    let header_size = read_u16(elf, 0x3a)? as usize;
+    let count = read_u16(elf, 0x3c)? as usize;
+    let names_index = read_u16(elf, 0x3e)? as usize;
+
+    let mut sections = Vec::with_capacity(count);
+    let mut name_offsets = Vec::with_capacity(count);
+    for index in 0..count {
+        let header = header_offset + index * header_size;
+        name_offsets.push(read_u32(elf, header)? as usize);
+        sections.push(Section {
+            name: String::new(),
+            kind: read_u32(elf, header + 0x04)?,
+            offset: read_u64(elf, header + 0x18)? as usize,
+            size: read_u64(elf, header + 0x20)? as usize,
+            link: read_u32(elf, header + 0x28)?,
+        });
+    }
+
+    let names = sections.get(names_index).ok_or("bad section name table index")?.offset;
+    for (section, name_offset) in sections.iter_mut().zip(name_offsets) {
+        section.name = read_str(elf, names + name_offset)?.to_string();
+    }
+    Ok(sections)
+}
+
+// Every defined function symbol, demangled and sorted by address
+fn function_symbols(elf: &[u8], sections: &[Section]) -> Result<Vec<Symbol>, String> {
+    let symtab = sections.iter()
+        .find(|section| section.kind == SHT_SYMTAB)
+        .ok_or("no symbol table, was the kernel stripped?")?;
+    let strtab = sections.get(symtab.link as usize).ok_or("bad string table index")?;
+
+    let mut symbols = Vec::new();
+    for entry in (symtab.offset..symtab.offset + symtab.size).step_by(SYMBOL_SIZE) {
+        let info = *elf.get(entry + 4).ok_or("truncated symbol table")?;
+        let address = read_u64(elf, entry + 8)?;
+        if info & 0xf != STT_FUNC || address == 0 {
+            continue;
+        }
+        let name = read_str(elf, strtab.offset + read_u32(elf, entry)? as usize)?;
+        symbols.push(Symbol {
+            address,
+            size: read_u64(elf, entry + 16)?,
+            name: format!("{:#}", rustc_demangle::demangle(name)),
+        });
+    }
+    symbols.sort_by_key(|symbol| symbol.address);
+    symbols.dedup_by_key(|symbol| symbol.address);
+    Ok(symbols)
+}
+
+fn encode(symbols: &[Symbol]) -> Vec<u8> {
+    let strings_offset = HEADER_SIZE + symbols.len() * ENTRY_SIZE;
+    let mut table = Vec::with_capacity(strings_offset);
+    let mut strings = Vec::new();
+
+    table.extend_from_slice(MAGIC);
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    table.extend_from_slice(&(strings_offset as u32).to_le_bytes());
+    for symbol in symbols {
+        table.extend_from_slice(&symbol.address.to_le_bytes());
+        table.extend_from_slice(&(symbol.size.min(u32::MAX as u64) as u32).to_le_bytes());
+        table.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+        strings.extend_from_slice(symbol.name.as_bytes());
+        strings.push(0);
+    }
+    table.extend_from_slice(&strings);
+    table
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, String> {
+    Ok(u16::from_le_bytes(read_bytes(data, offset)?))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    Ok(u32::from_le_bytes(read_bytes(data, offset)?))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, String> {
+    Ok(u64::from_le_bytes(read_bytes(data, offset)?))
+}
+
+fn read_bytes<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N], String> {
+    data.get(offset..offset + N)
+        .map(|bytes| bytes.try_into().unwrap())
+        .ok_or_else(|| format!("ELF file truncated at {:#x}", offset))
+}
+
+fn read_str(data: &[u8], offset: usize) -> Result<&str, String> {
+    let bytes = data.get(offset..).ok_or("string outside of the file")?;
+    let end = bytes.iter().position(|&b| b == 0).ok_or("unterminated string")?;
+    std::str::from_utf8(&bytes[..end]).map_err(|_| "string is not UTF-8".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_layout() {
+        let symbols = [
+            Symbol { address: 0x1000, size: 0x20, name: "kernel::a".into() },
+            Symbol { address: 0x1020, size: 0x8, name: "b".into() },
+        ];
+        let table = encode(&symbols);
+
+        assert_eq!(&table[..8], MAGIC);
+        assert_eq!(read_u32(&table, 8).unwrap(), 2);
+        let strings = read_u32(&table, 12).unwrap() as usize;
+        assert_eq!(strings, HEADER_SIZE + 2 * ENTRY_SIZE);
+
+        let second = HEADER_SIZE + ENTRY_SIZE;
+        assert_eq!(read_u64(&table, second).unwrap(), 0x1020);
+        assert_eq!(read_u32(&table, second + 8).unwrap(), 0x8);
+        let name = read_u32(&table, second + 12).unwrap() as usize;
+        assert_eq!(read_str(&table, strings + name).unwrap(), "b");
+        assert_eq!(table.len(), strings + "kernel::a\0b\0".len());
+    }
+
+    // The test binary is an ELF with a symbol table too
+    #[test]
+    fn reads_own_symbols() {
+        let elf = fs::read(env::current_exe().unwrap()).unwrap();
+        let sections = sections(&elf).unwrap();
+        let symbols = function_symbols(&elf, &sections).unwrap();
+
+        assert!(symbols.windows(2).all(|pair| pair[0].address < pair[1].address));
+        let find = |name: &str| symbols.iter()
+            .find(|symbol| symbol.name.ends_with(name))
+            .unwrap_or_else(|| panic!("{} not found", name))
+            .address;
+
+        // The test binary is position independent, so only distances match the symbols
+        let distance = find("tests::reads_own_symbols").wrapping_sub(find("tests::encode_layout"));
+        let runtime = (reads_own_symbols as fn() as usize).wrapping_sub(encode_layout as fn() as usize);
+        assert_eq!(distance, runtime as u64);
+    }
+
+    #[test]
+    fn table_has_to_fit_the_section() {
+        let table = encode(&[Symbol { address: 0x1000, size: 0x20, name: "kernel::a".into() }]);
+        let mut elf = vec![0; 4 + table.len()];
+        elf[4..12].copy_from_slice(MAGIC);
+        let section = |size| Section { name: ".ksymtab".into(), kind: 1, offset: 4, size, link: 0 };
+
+        assert!(patch(&mut elf, &section(table.len() - 1), &table).unwrap_err().contains("raise KSYMTAB_SIZE"));
+        patch(&mut elf, &section(table.len()), &table).unwrap();
+        assert_eq!(&elf[4..], &table[..]);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(sections(b"#!/bin/sh\n").is_err());
+        assert!(sections(b"\x7fELF\x01\x01").is_err()); // 32 bit
+    }
+}